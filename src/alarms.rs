@@ -0,0 +1,224 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use failure::Fallible;
+use xmltree::Element;
+
+use crate::upnp::{self, Service};
+use crate::util;
+
+/// An alarm as stored by the household, alarms aren't tied to the speaker they're read from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Alarm {
+    pub id: u32,
+    /// Local time the alarm goes off, as `hh:mm:ss`
+    pub start_time: String,
+    /// How long the alarm plays for before stopping by itself
+    #[serde(serialize_with = "util::serialize_duration", deserialize_with = "util::deserialize_duration")]
+    pub duration: Duration,
+    /// `ONCE`, `DAILY`, `WEEKDAYS`, `WEEKENDS` or `ON_` followed by day numbers (0 is Sunday)
+    pub recurrence: String,
+    pub room_uuid: String,
+    /// Name of the alarm's room, if it's a room we know about
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub room: Option<String>,
+    pub enabled: bool,
+    pub volume: u8,
+    pub program_uri: String,
+    #[serde(skip)]
+    pub program_metadata: String,
+    pub play_mode: String,
+    pub include_linked_zones: bool,
+}
+
+/// The speaker's idea of the current time, which is what alarms go off by.
+pub struct TimeNow {
+    pub utc: String,
+    pub local: String,
+    pub time_zone: String,
+    pub auto_adjust_dst: bool,
+    pub time_server: Option<String>,
+}
+
+/// Read the speaker's clock and time zone settings.
+pub async fn time_now(ip: IpAddr) -> Fallible<TimeNow> {
+    let (now, zone, server) = futures::join!(
+        upnp::call(ip, Service::AlarmClock, "GetTimeNow", &[]),
+        upnp::call(ip, Service::AlarmClock, "GetTimeZoneAndRule", &[]),
+        upnp::call(ip, Service::AlarmClock, "GetTimeServer", &[]),
+    );
+    let (now, zone) = (now?, zone?);
+
+    let field = |el: &Element, name: &str| upnp::child_text(el, name)
+        .ok_or_else(|| format_err!("Speaker's clock is missing {}", name));
+
+    Ok(TimeNow {
+        utc: field(&now, "CurrentUTCTime")?,
+        local: field(&now, "CurrentLocalTime")?,
+        time_zone: field(&zone, "CurrentTimeZone")?,
+        auto_adjust_dst: field(&zone, "AutoAdjustDst")? == "1",
+        // older firmware doesn't have a configurable time server
+        time_server: server.ok().and_then(|s| upnp::child_text(&s, "CurrentTimeServer")),
+    })
+}
+
+/// Every alarm in the household.
+pub async fn list(ip: IpAddr) -> Fallible<Vec<Alarm>> {
+    let response = upnp::call(ip, Service::AlarmClock, "ListAlarms", &[]).await?;
+    let alarms = upnp::child_text(&response, "CurrentAlarmList")
+        .ok_or_else(|| format_err!("ListAlarms response is missing CurrentAlarmList"))?;
+
+    let mut alarms = upnp::elements(&Element::parse(alarms.as_bytes())?, "Alarm")
+        .map(parse_alarm)
+        .collect::<Fallible<Vec<_>>>()?;
+
+    alarms.sort_by_key(|a| a.id);
+
+    Ok(alarms)
+}
+
+/// The alarm with the given ID.
+pub async fn get(ip: IpAddr, id: u32) -> Fallible<Alarm> {
+    let alarms = list(ip).await?;
+
+    if let Some(alarm) = alarms.iter().find(|a| a.id == id) {
+        return Ok(alarm.clone());
+    }
+
+    if alarms.is_empty() {
+        return Err(not_found!("There's no alarm with ID {}, no alarms are set", id));
+    }
+
+    Err(not_found!(
+        "There's no alarm with ID {}, known alarms: {}",
+        id,
+        alarms.iter().map(|a| a.id.to_string()).collect::<Vec<_>>().join(", "),
+    ))
+}
+
+/// Add a new alarm to the household, returning the ID it was given. The alarm's own ID is
+/// ignored.
+pub async fn create(ip: IpAddr, alarm: &Alarm) -> Fallible<u32> {
+    let (duration, volume) = (hms(alarm.duration), alarm.volume.to_string());
+
+    let response = upnp::call(ip, Service::AlarmClock, "CreateAlarm", &[
+        ("StartLocalTime", &alarm.start_time),
+        ("Duration", &duration),
+        ("Recurrence", &alarm.recurrence),
+        ("Enabled", if alarm.enabled { "1" } else { "0" }),
+        ("RoomUUID", &alarm.room_uuid),
+        ("ProgramURI", &alarm.program_uri),
+        ("ProgramMetaData", &alarm.program_metadata),
+        ("PlayMode", &alarm.play_mode),
+        ("Volume", &volume),
+        ("IncludeLinkedZones", if alarm.include_linked_zones { "1" } else { "0" }),
+    ]).await?;
+
+    upnp::child_text(&response, "AssignedID")
+        .and_then(|id| id.parse().ok())
+        .ok_or_else(|| format_err!("CreateAlarm response is missing AssignedID"))
+}
+
+/// Write every field of `alarm` back to the household, replacing the alarm with the same ID.
+pub async fn update(ip: IpAddr, alarm: &Alarm) -> Fallible<()> {
+    let (id, duration, volume) = (alarm.id.to_string(), hms(alarm.duration), alarm.volume.to_string());
+
+    upnp::call(ip, Service::AlarmClock, "UpdateAlarm", &[
+        ("ID", &id),
+        ("StartLocalTime", &alarm.start_time),
+        ("Duration", &duration),
+        ("Recurrence", &alarm.recurrence),
+        ("Enabled", if alarm.enabled { "1" } else { "0" }),
+        ("RoomUUID", &alarm.room_uuid),
+        ("ProgramURI", &alarm.program_uri),
+        ("ProgramMetaData", &alarm.program_metadata),
+        ("PlayMode", &alarm.play_mode),
+        ("Volume", &volume),
+        ("IncludeLinkedZones", if alarm.include_linked_zones { "1" } else { "0" }),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Remove an alarm from the household for good.
+pub async fn delete(ip: IpAddr, id: u32) -> Fallible<()> {
+    upnp::call(ip, Service::AlarmClock, "DestroyAlarm", &[("ID", &id.to_string())]).await?;
+
+    Ok(())
+}
+
+/// Normalise a time of day given as `hh:mm` or `hh:mm:ss` to the `hh:mm:ss` alarms expect.
+pub fn parse_time(time: &str) -> Fallible<String> {
+    let parts = time.split(':').map(str::parse::<u8>).collect::<Result<Vec<_>, _>>();
+
+    match parts.as_deref() {
+        Ok([h, m]) if *h < 24 && *m < 60 => Ok(format!("{:02}:{:02}:00", h, m)),
+        Ok([h, m, s]) if *h < 24 && *m < 60 && *s < 60 => Ok(format!("{:02}:{:02}:{:02}", h, m, s)),
+        _ => Err(invalid_argument!("Expected a time of day such as 07:00, got {}", time)),
+    }
+}
+
+/// Validate a recurrence: `ONCE`, `DAILY`, `WEEKDAYS`, `WEEKENDS` or `ON_` followed by the
+/// numbers of the days the alarm should go off (0 is Sunday, so `ON_135` is Monday, Wednesday
+/// and Friday).
+pub fn parse_recurrence(recurrence: &str) -> Fallible<String> {
+    let recurrence = recurrence.to_uppercase();
+
+    match recurrence.as_str() {
+        "ONCE" | "DAILY" | "WEEKDAYS" | "WEEKENDS" => return Ok(recurrence),
+        _ => {},
+    }
+
+    if let Some(days) = recurrence.strip_prefix("ON_") {
+        let mut seen = [false; 7];
+
+        let valid = !days.is_empty() && days.chars().all(|d| match d.to_digit(10) {
+            Some(d) if d < 7 && !seen[d as usize] => {
+                seen[d as usize] = true;
+                true
+            },
+            _ => false,
+        });
+
+        if valid {
+            return Ok(recurrence);
+        }
+    }
+
+    Err(invalid_argument!("Expected a recurrence of ONCE, DAILY, WEEKDAYS, WEEKENDS or ON_ followed by day numbers (0 is Sunday), got {}", recurrence))
+}
+
+/// Switch an alarm on or off, leaving everything else about it as it was.
+pub async fn set_enabled(ip: IpAddr, id: u32, enabled: bool) -> Fallible<Alarm> {
+    let mut alarm = get(ip, id).await?;
+    alarm.enabled = enabled;
+
+    update(ip, &alarm).await?;
+
+    Ok(alarm)
+}
+
+/// Alarms always want the hours, unlike `util::duration_to_hms`.
+fn hms(d: Duration) -> String {
+    format!("{:02}:{:02}:{:02}", d.as_secs() / 3600, d.as_secs() % 3600 / 60, d.as_secs() % 60)
+}
+
+fn parse_alarm(el: &Element) -> Fallible<Alarm> {
+    let id = upnp::attribute(el, "ID")?;
+    let volume = upnp::attribute(el, "Volume")?;
+
+    Ok(Alarm {
+        id: id.parse().map_err(|_| format_err!("Alarm has an invalid ID: {}", id))?,
+        start_time: upnp::attribute(el, "StartTime")?,
+        duration: util::hms_to_duration(&upnp::attribute(el, "Duration")?)?,
+        recurrence: upnp::attribute(el, "Recurrence")?,
+        room_uuid: upnp::attribute(el, "RoomUUID")?,
+        room: None,
+        enabled: upnp::attribute(el, "Enabled")? == "1",
+        volume: volume.parse().map_err(|_| format_err!("Alarm {} has an invalid volume: {}", id, volume))?,
+        program_uri: upnp::attribute(el, "ProgramURI")?,
+        program_metadata: upnp::attribute(el, "ProgramMetaData").unwrap_or_default(),
+        play_mode: upnp::attribute(el, "PlayMode")?,
+        include_linked_zones: upnp::attribute(el, "IncludeLinkedZones").map(|v| v == "1").unwrap_or(false),
+    })
+}
@@ -0,0 +1,157 @@
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use failure::Fallible;
+
+/// Settings read from the config file. Everything is optional, and flags given on the command
+/// line always win over what's in here.
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Controller to use when `-c` isn't given, by IP, room name or UUID
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub controller: Option<String>,
+    /// Seconds to wait for speakers to respond when discovering them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub discovery_timeout: Option<u64>,
+    /// Highest volume any room can be set to
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_volume: Option<u8>,
+    /// Output results as JSON, as if `json` was given
+    pub json: bool,
+    /// Leave the emoji out of human readable output
+    pub no_emoji: bool,
+    /// Short names for rooms, ie. `lr = "Living Room"`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub aliases: BTreeMap<String, String>,
+    /// Highest volume particular rooms can be set to, overriding `max_volume`
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub room_max_volume: BTreeMap<String, u8>,
+}
+impl std::fmt::Display for Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", toml::to_string(self).map_err(|_| std::fmt::Error)?.trim_end())
+    }
+}
+
+static CONFIG: Mutex<Option<Config>> = Mutex::new(None);
+
+/// Path of the config file, `$SONOS_CLI_CONFIG` if it's set.
+pub fn path() -> PathBuf {
+    std::env::var_os("SONOS_CLI_CONFIG")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| dirs::config_dir()
+            .unwrap_or_else(std::env::temp_dir)
+            .join("sonos-cli")
+            .join("config.toml"))
+}
+
+/// Read the config file and make it the one `get` returns. A missing file is the same as an
+/// empty one.
+pub fn load() -> Fallible<Config> {
+    let path = path();
+
+    let config = match std::fs::read_to_string(&path) {
+        Ok(contents) => toml::from_str::<Config>(&contents)
+            .map_err(|e| format_err!("Couldn't parse config file {}: {}", path.display(), e))?,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Config::default(),
+        Err(e) => bail!("Couldn't read config file {}: {}", path.display(), e),
+    };
+
+    if let Some((room, volume)) = config.max_volume.iter().map(|v| ("max_volume", v))
+        .chain(config.room_max_volume.iter().map(|(room, v)| (room.as_str(), v)))
+        .find(|(_, volume)| **volume > 100)
+    {
+        bail!("Volume caps in {} have to be between 0 and 100, {} is {}", path.display(), room, volume);
+    }
+
+    *CONFIG.lock().unwrap() = Some(config.clone());
+
+    Ok(config)
+}
+
+/// Set the default controller in the config file to the given UUID, with the room's name as a
+/// comment so it's obvious which room it is. `None` removes it. Everything else in the file is
+/// left as it was.
+pub fn set_controller(controller: Option<(&str, &str)>) -> Fallible<()> {
+    let path = path();
+
+    let contents = match std::fs::read_to_string(&path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => bail!("Couldn't read config file {}: {}", path.display(), e),
+    };
+
+    if controller.is_none() && contents.is_empty() {
+        return Ok(());
+    }
+
+    // the controller is a top level key, so it can only be set before the first table
+    let first_table = contents.lines()
+        .position(|line| line.trim_start().starts_with('['))
+        .unwrap_or_else(|| contents.lines().count());
+
+    let is_controller = |line: &str| line.trim_start()
+        .strip_prefix("controller")
+        .is_some_and(|rest| rest.trim_start().starts_with('='));
+
+    let mut lines = contents.lines()
+        .enumerate()
+        .filter(|(i, line)| *i >= first_table || !is_controller(line))
+        .map(|(_, line)| line.to_string())
+        .collect::<Vec<_>>();
+
+    if let Some((uuid, name)) = controller {
+        lines.insert(0, format!("controller = {} # {}", toml::Value::from(uuid), name.replace('\n', " ")));
+    }
+
+    let mut contents = lines.join("\n");
+    contents.push('\n');
+
+    toml::from_str::<Config>(&contents)
+        .map_err(|e| format_err!("Couldn't update config file {}: {}", path.display(), e))?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+
+    std::fs::write(&path, contents)
+        .map_err(|e| format_err!("Couldn't write config file {}: {}", path.display(), e))?;
+
+    load()?;
+
+    Ok(())
+}
+
+/// The loaded config, the defaults if `load` hasn't been called.
+pub fn get() -> Config {
+    CONFIG.lock().unwrap().clone().unwrap_or_default()
+}
+
+/// The room an alias stands for, or `identifier` itself if it isn't an alias. Aliases are matched
+/// ignoring case.
+pub fn resolve_alias(identifier: &str) -> String {
+    get().aliases.into_iter()
+        .find(|(alias, _)| alias.eq_ignore_ascii_case(identifier.trim()))
+        .map(|(_, room)| room)
+        .unwrap_or_else(|| identifier.to_string())
+}
+
+/// Bring `volume` down to the cap configured for `room`, if there is one.
+pub fn cap_volume(room: &str, volume: u8) -> u8 {
+    let config = get();
+
+    let cap = config.room_max_volume.iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(room))
+        .map(|(_, cap)| *cap)
+        .or(config.max_volume);
+
+    match cap {
+        Some(cap) if volume > cap => {
+            warn!("{} is capped at volume {} by the config file", room, cap);
+            cap
+        },
+        _ => volume,
+    }
+}
@@ -0,0 +1,49 @@
+use crate::upnp::escape;
+
+/// A single DIDL-Lite item, used as the metadata passed alongside a URI so the speaker knows
+/// what it's playing. Some sources (radio in particular) won't play without it.
+#[derive(Debug, Default)]
+pub struct Item<'a> {
+    pub id: &'a str,
+    pub parent_id: &'a str,
+    pub title: &'a str,
+    pub class: &'a str,
+    pub creator: Option<&'a str>,
+    pub album: Option<&'a str>,
+    pub album_art: Option<&'a str>,
+    /// Account descriptor for music service items, eg. `SA_RINCON65031_` for TuneIn
+    pub desc: Option<&'a str>,
+}
+
+impl Item<'_> {
+    pub fn to_didl(&self) -> String {
+        let mut item = format!(
+            r#"<item id="{}" parentID="{}" restricted="true"><dc:title>{}</dc:title><upnp:class>{}</upnp:class>"#,
+            escape(self.id), escape(self.parent_id), escape(self.title), escape(self.class),
+        );
+
+        if let Some(creator) = self.creator {
+            item.push_str(&format!("<dc:creator>{}</dc:creator>", escape(creator)));
+        }
+
+        if let Some(album) = self.album {
+            item.push_str(&format!("<upnp:album>{}</upnp:album>", escape(album)));
+        }
+
+        if let Some(album_art) = self.album_art {
+            item.push_str(&format!("<upnp:albumArtURI>{}</upnp:albumArtURI>", escape(album_art)));
+        }
+
+        if let Some(desc) = self.desc {
+            item.push_str(&format!(
+                r#"<desc id="cdudn" nameSpace="urn:schemas-rinconnetworks-com:metadata-1-0/">{}</desc>"#,
+                escape(desc),
+            ));
+        }
+
+        format!(
+            r#"<DIDL-Lite xmlns:dc="http://purl.org/dc/elements/1.1/" xmlns:upnp="urn:schemas-upnp-org:metadata-1-0/upnp/" xmlns:r="urn:schemas-rinconnetworks-com:metadata-1-0/" xmlns="urn:schemas-upnp-org:metadata-1-0/DIDL-Lite/">{}</item></DIDL-Lite>"#,
+            item,
+        )
+    }
+}
@@ -1,108 +1,871 @@
-use std::net::IpAddr;
-use std::time::Duration;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use sonos::Speaker;
 use failure::Fallible;
 
-use tokio::io::{self, AsyncWriteExt, AsyncReadExt};
-use futures::future::try_join_all;
+use tokio::io::AsyncReadExt;
 
-pub async fn find_speaker_by_name(name: &str) -> Fallible<Speaker> {
-    let mut speakers = discover(true, false).await?;
+use crate::{config, util};
+use futures::future::{join_all, try_join_all, Either};
 
-    let mut min = 100;
+/// Parse an IP address, accepting IPv6 addresses in brackets as they're written in URLs
+/// (`[fd00::1]`) as well as bare.
+pub fn parse_ip(s: &str) -> Option<IpAddr> {
+    s.strip_prefix('[')
+        .and_then(|s| s.strip_suffix(']'))
+        .unwrap_or(s)
+        .parse()
+        .ok()
+}
 
-    speakers.sort_by(|a, b| {
-        let a = strsim::damerau_levenshtein(&a.name, name);
-        let b = strsim::damerau_levenshtein(&b.name, name);
+/// Resolve a speaker from either its IP address, its UUID (`RINCON_...`) or its room name. Aliases
+/// from the config file are expanded first.
+pub async fn find_speaker(cache_file: &Path, identifier: &str) -> Fallible<Speaker> {
+    let identifier = &config::resolve_alias(identifier);
 
-        if a < min { min = a; }
-        if b < min { min = b; }
+    match parse_ip(identifier) {
+        Some(ip) => Speaker::from_ip(ip).await,
+        None if identifier.get(..7).is_some_and(|prefix| prefix.eq_ignore_ascii_case("RINCON_")) => {
+            find_speaker_by_uuid(cache_file, identifier).await
+        },
+        None => find_speaker_by_name(cache_file, identifier).await,
+    }
+}
 
-        a.cmp(&b)
-    });
+/// Where the cache used to live before it moved to a per-user directory.
+const LEGACY_CACHE_FILE: &str = "/tmp/sonos-cli-speakers";
+
+/// Make sure the speaker cache can be kept at `path`, so a cache asked for with `--cache-file`
+/// fails straight away rather than on the first discovery.
+pub fn check_cache_file(path: &Path) -> Fallible<()> {
+    if path.is_dir() {
+        return Err(invalid_argument!("Expected a file for the speaker cache, {} is a directory", path.display()));
+    }
+
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    // permissions don't tell the whole story (read-only mounts, ACLs), so actually try it
+    let mut probe = parent.join(path.file_name().unwrap_or_default()).into_os_string();
+    probe.push(format!(".{}.probe", std::process::id()));
+
+    std::fs::create_dir_all(&parent)
+        .and_then(|_| std::fs::write(&probe, b""))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map_err(|e| format_err!("Can't keep the speaker cache at {}, {} isn't writable: {}", path.display(), parent.display(), e))?;
+
+    Ok(())
+}
+
+/// Where the cache is kept when no other file has been asked for.
+pub fn default_cache_file() -> PathBuf {
+    util::cache_dir().join("speakers.json")
+}
+
+/// How long to wait for speakers to answer an SSDP search, in seconds.
+static DISCOVERY_TIMEOUT: AtomicU64 = AtomicU64::new(2);
+
+/// Set how long discovery waits for speakers to respond, between 1 and 30 seconds.
+pub fn set_timeout(secs: u64) -> Fallible<()> {
+    if !(1..=30).contains(&secs) {
+        return Err(invalid_argument!("Discovery timeout must be between 1 and 30 seconds, got {}", secs));
+    }
+
+    DISCOVERY_TIMEOUT.store(secs, Ordering::Relaxed);
+
+    Ok(())
+}
+
+/// How long discovery waits for speakers to respond.
+pub fn timeout() -> Duration {
+    Duration::from_secs(DISCOVERY_TIMEOUT.load(Ordering::Relaxed))
+}
+
+/// Whether discovery shows its countdown, turned off for `--json` and `--quiet`.
+static SHOW_PROGRESS: AtomicBool = AtomicBool::new(true);
+
+pub fn set_progress(show: bool) {
+    SHOW_PROGRESS.store(show, Ordering::Relaxed);
+}
+
+/// How many SSDP searches to send during discovery, speakers regularly miss a single one.
+static DISCOVERY_ROUNDS: AtomicU64 = AtomicU64::new(3);
+
+/// Set how many searches discovery sends, spread evenly across the discovery timeout.
+pub fn set_rounds(rounds: u64) -> Fallible<()> {
+    if !(1..=10).contains(&rounds) {
+        return Err(invalid_argument!("Discovery rounds must be between 1 and 10, got {}", rounds));
+    }
+
+    DISCOVERY_ROUNDS.store(rounds, Ordering::Relaxed);
+
+    Ok(())
+}
 
-    if min > 5 {
-        bail!("Couldn't find a speaker by that name");
+/// Address ranges to probe directly on top of the SSDP search, for networks where multicast
+/// doesn't make it to the speakers.
+static SCAN_RANGES: Mutex<Vec<(Ipv4Addr, u8)>> = Mutex::new(Vec::new());
+
+/// Probe every address in the given CIDR range (eg. `192.168.10.0/24`) during discovery.
+pub fn add_scan_range(cidr: &str) -> Fallible<()> {
+    let mut parts = cidr.splitn(2, '/');
+
+    let (network, prefix) = match (parts.next().map(str::parse::<Ipv4Addr>), parts.next().map(str::parse::<u8>)) {
+        (Some(Ok(network)), Some(Ok(prefix))) => (network, prefix),
+        _ => return Err(invalid_argument!("Expected an IPv4 range such as 192.168.10.0/24, got {}", cidr)),
+    };
+
+    // a /16 is already 65k addresses, anything bigger is almost certainly a typo
+    if !(16..=32).contains(&prefix) {
+        return Err(invalid_argument!("Scan ranges must be between /16 and /32, got /{}", prefix));
+    }
+
+    SCAN_RANGES.lock().expect("scan ranges poisoned").push((network, prefix));
+
+    Ok(())
+}
+
+/// Local address to send the SSDP search from, for machines on more than one network.
+static BIND_ADDRESS: Mutex<Option<Ipv4Addr>> = Mutex::new(None);
+
+/// Send the SSDP search from the given local address.
+pub fn set_bind_address(address: Ipv4Addr) {
+    *BIND_ADDRESS.lock().expect("bind address poisoned") = Some(address);
+}
+
+/// Send the SSDP search out of the named network interface (eg. `eth1`).
+pub fn set_interface(name: &str) -> Fallible<()> {
+    let interfaces = get_if_addrs::get_if_addrs()?;
+
+    let address = interfaces.iter()
+        .filter(|i| i.name == name)
+        .find_map(|i| match i.ip() {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(_) => None,
+        });
+
+    match address {
+        Some(address) => {
+            set_bind_address(address);
+            Ok(())
+        },
+        None => {
+            let mut available = interfaces.iter()
+                .filter(|i| i.ip().is_ipv4())
+                .map(|i| i.name.as_str())
+                .collect::<Vec<_>>();
+            available.dedup();
+
+            Err(not_found!("Couldn't find an interface named {} with an IPv4 address, available interfaces: {}", name, available.join(", ")))
+        },
     }
+}
 
-    let speaker = speakers.remove(0);
+/// Run a fresh discovery whenever any cached speaker can't be reached, rather than dropping it
+/// from the cache and carrying on with the rest.
+static STRICT_CACHE: AtomicBool = AtomicBool::new(false);
 
-    if min > 2 {
-        let mut stdin = io::stdin();
-        let mut stdout = io::stdout();
+pub fn set_strict_cache(strict: bool) {
+    STRICT_CACHE.store(strict, Ordering::Relaxed);
+}
 
-        stdout.write_all(format!("Couldn't find speaker '{}', did you mean {}? [Y/n] ", name, speaker.name).as_bytes()).await?;
-        stdout.flush().await?;
+/// How long a discovery stays fresh before we transparently run another one, in seconds.
+static CACHE_TTL: AtomicU64 = AtomicU64::new(24 * 60 * 60);
 
-        let input = stdin
-            .read_u8()
-            .await? as char;
+pub fn set_cache_ttl(ttl: Duration) {
+    CACHE_TTL.store(ttl.as_secs(), Ordering::Relaxed);
+}
 
-        if input != 'y' && input != 'Y' {
-            bail!("Couldn't find a speaker by that name");
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Cache {
+    /// Seconds since the epoch of the discovery the cache was written by
+    discovered: u64,
+    speakers: Vec<CachedSpeaker>,
+    /// Speakers added by hand with `speaker add` since discovery can't find them, these are kept
+    /// when the cache is rewritten and always included in discovery
+    #[serde(default)]
+    manual: Vec<CachedSpeaker>,
+}
+impl Cache {
+    fn new(speakers: Vec<CachedSpeaker>, manual: Vec<CachedSpeaker>) -> Self {
+        Self {
+            discovered: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            speakers,
+            manual,
         }
     }
 
-    Ok(speaker)
+    fn is_fresh(&self) -> bool {
+        let age = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+            .saturating_sub(self.discovered);
+
+        age < CACHE_TTL.load(Ordering::Relaxed)
+    }
 }
 
-pub async fn discover(pretty: bool, invalidate: bool) -> Fallible<Vec<Speaker>> {
-    use serde::Serialize;
+/// Enough about a speaker to pick it out by name without having to ask it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CachedSpeaker {
+    pub ip: IpAddr,
+    pub name: String,
+    pub uuid: String,
+    /// Missing from caches written before it was added
+    #[serde(default)]
+    pub model: String,
+}
+impl From<&Speaker> for CachedSpeaker {
+    fn from(speaker: &Speaker) -> Self {
+        Self {
+            ip: speaker.ip,
+            name: speaker.name.clone(),
+            uuid: speaker.uuid.clone(),
+            model: speaker.model.clone(),
+        }
+    }
+}
 
-    const CACHE_FILE_NAME: &str = "/tmp/sonos-cli-speakers";
+/// More than one room matches the name given equally well, and there was nobody to ask which
+/// one was meant.
+#[derive(Debug, Fail)]
+#[fail(display = "'{}' matches more than one room:\n{}", name, candidates)]
+pub struct AmbiguousName {
+    pub name: String,
+    pub candidates: String,
+}
 
-    if !invalidate {
-        if let Ok(mut cache) = tokio::fs::File::open(CACHE_FILE_NAME).await {
-            let mut contents: Vec<u8> = vec![];
-            cache.read_to_end(&mut contents).await?;
+/// Resolve a speaker by its room name using the names in the cache, so only the speaker that
+/// matches has to be contacted. If the cache turns out to be out of date the name is matched
+/// again against a fresh discovery.
+pub async fn find_speaker_by_name(cache_file: &Path, name: &str) -> Fallible<Speaker> {
+    let cached = match_name(name, cached_speakers(cache_file, true, false).await?).await?;
+
+    match Speaker::from_ip(cached.ip).await {
+        Ok(speaker) if speaker.uuid == cached.uuid && speaker.name == cached.name => return Ok(speaker),
+        _ => debug!("{} has changed since it was cached, discovering again", cached.name),
+    }
+
+    let mut devices = discover(cache_file, true, true).await?;
+    let found = match_name(name, devices.iter().map(CachedSpeaker::from).collect()).await?;
 
-            let cache: Vec<IpAddr> = serde_json::from_slice(contents.as_ref())?;
+    let i = devices.iter()
+        .position(|s| s.uuid == found.uuid)
+        .expect("matched speaker came from the discovered devices");
 
-            return try_join_all(cache.into_iter().map(Speaker::from_ip)).await;
+    Ok(devices.swap_remove(i))
+}
+
+/// The speaker whose room name best matches `name`.
+async fn match_name(name: &str, speakers: Vec<CachedSpeaker>) -> Fallible<CachedSpeaker> {
+    let found = util::fuzzy_match(&speakers, name, |s| &s.name);
+
+    match (found.confidence, found.matches.as_slice()) {
+        (util::Confidence::None, _) => Err(not_found!("Couldn't find a speaker named '{}'", name)),
+        (util::Confidence::Fuzzy, [speaker]) => {
+            if !util::confirm(&format!("Couldn't find speaker '{}', did you mean {}?", name, speaker.name)).await? {
+                return Err(not_found!("Couldn't find a speaker named '{}'", name));
+            }
+
+            Ok((*speaker).clone())
+        },
+        _ => Ok(pick(name, found.matches).await?.expect("at least one speaker matched").clone()),
+    }
+}
+
+/// The only room in `matches`, or the one the user picks if there's more than one.
+async fn pick<'a>(name: &str, mut matches: Vec<&'a CachedSpeaker>) -> Fallible<Option<&'a CachedSpeaker>> {
+    if matches.len() < 2 {
+        return Ok(matches.pop());
+    }
+
+    let candidates = matches.iter()
+        .map(|s| format!("{} ({})", s.name, s.ip))
+        .collect::<Vec<_>>();
+
+    match util::choose(&format!("'{}' matches more than one room:", name), &candidates).await? {
+        Some(i) => Ok(Some(matches[i])),
+        None => Err(AmbiguousName {
+            name: name.to_string(),
+            candidates: candidates.iter().map(|c| format!("  {}", c)).collect::<Vec<_>>().join("\n"),
+        }.into()),
+    }
+}
+
+/// Resolve a speaker from its UUID (`RINCON_...`), which unlike names and IPs never changes.
+pub async fn find_speaker_by_uuid(cache_file: &Path, uuid: &str) -> Fallible<Speaker> {
+    let cached = cached_speakers(cache_file, true, false).await?
+        .into_iter()
+        .find(|s| s.uuid.eq_ignore_ascii_case(uuid));
+
+    if let Some(speaker) = cached {
+        return hydrate(cache_file, &speaker).await;
+    }
+
+    let mut speakers = discover(cache_file, true, true).await?;
+
+    if let Some(i) = speakers.iter().position(|s| s.uuid.eq_ignore_ascii_case(uuid)) {
+        return Ok(speakers.swap_remove(i));
+    }
+
+    speakers.sort_by(|a, b| a.name.cmp(&b.name));
+
+    Err(not_found!(
+        "Couldn't find a speaker with the UUID {}, known speakers are:\n{}",
+        uuid,
+        speakers.iter().map(|s| format!("  {} ({})", s.uuid, s.name)).collect::<Vec<_>>().join("\n"),
+    ))
+}
+
+/// Turn a cached speaker into a full `Speaker`, running a fresh discovery if it's moved to
+/// another IP since it was cached.
+async fn hydrate(cache_file: &Path, cached: &CachedSpeaker) -> Fallible<Speaker> {
+    match Speaker::from_ip(cached.ip).await {
+        Ok(speaker) if speaker.uuid == cached.uuid => Ok(speaker),
+        _ => discover(cache_file, true, true).await?
+            .into_iter()
+            .find(|s| s.uuid == cached.uuid)
+            .ok_or_else(|| format_err!("{} can't be found on the network anymore", cached.name)),
+    }
+}
+
+/// Take an advisory lock on the cache, shared for reading and exclusive for writing, so
+/// invocations running side by side don't read a half written cache. The lock lives on a file
+/// next to the cache since writes replace the cache file itself, and is released on drop.
+async fn lock_cache(cache_file: &Path, exclusive: bool) -> std::io::Result<std::fs::File> {
+    use fs2::FileExt;
+
+    let mut path = cache_file.as_os_str().to_os_string();
+    path.push(".lock");
+
+    // waiting on the lock blocks, so it's done off the reactor
+    tokio::task::spawn_blocking(move || {
+        let lock = std::fs::OpenOptions::new()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(path)?;
+
+        if exclusive {
+            lock.lock_exclusive()?;
+        } else {
+            lock.lock_shared()?;
+        }
+
+        Ok(lock)
+    }).await.map_err(std::io::Error::other)?
+}
+
+/// Take the lock held while the cache is being rebuilt or changed, so invocations running side by
+/// side don't all discover at once or undo each other's changes. Returns whether another
+/// invocation had it first, in which case this waited for it to finish.
+///
+/// The wait is only as long as a discovery could take, so a lock left behind by a hung invocation
+/// fails with `TimedOut` rather than blocking forever and the caller goes ahead without it.
+async fn lock_updates(cache_file: &Path) -> std::io::Result<(std::fs::File, bool)> {
+    use fs2::FileExt;
+
+    const POLL_INTERVAL: Duration = Duration::from_millis(100);
+    // on top of the searches themselves, for contacting every speaker that answered
+    const MARGIN: Duration = Duration::from_secs(5);
+
+    let mut path = cache_file.as_os_str().to_os_string();
+    path.push(".update.lock");
+
+    let lock = std::fs::OpenOptions::new()
+        .create(true)
+        .truncate(false)
+        .write(true)
+        .open(path)?;
+
+    if lock.try_lock_exclusive().is_ok() {
+        return Ok((lock, false));
+    }
+
+    debug!("Another invocation is updating the speaker cache, waiting for it");
+
+    let rounds = DISCOVERY_ROUNDS.load(Ordering::Relaxed) as u32;
+    let deadline = std::time::Instant::now() + timeout() * rounds + MARGIN;
+
+    // polled rather than blocked on so giving up doesn't leave a thread stuck waiting
+    loop {
+        tokio::time::delay_for(POLL_INTERVAL).await;
+
+        if lock.try_lock_exclusive().is_ok() {
+            return Ok((lock, true));
         }
+
+        if std::time::Instant::now() >= deadline {
+            debug!("Gave up waiting for the speaker cache, carrying on without it");
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+    }
+}
+
+/// Replace the cache with `cache`.
+async fn try_write_cache(cache_file: &Path, cache: &Cache) -> Fallible<()> {
+    if let Some(parent) = cache_file.parent() {
+        tokio::fs::create_dir_all(parent).await?;
     }
 
-    if pretty {
-        tokio::spawn(async {
-            let mut stdout = io::stdout();
+    let _lock = lock_cache(cache_file, true).await?;
+
+    // write alongside and rename over the cache so readers never see a partial file
+    let mut tmp = cache_file.as_os_str().to_os_string();
+    tmp.push(format!(".{}.tmp", std::process::id()));
 
-            const TWO: &str = "\u{23F2}\u{FE0F}  Give me 2 secs to discover your devices...";
-            const ONE: &str = "\u{23F2}\u{FE0F}  Give me a sec to discover your devices...";
+    tokio::fs::write(&tmp, serde_json::to_vec(cache)?).await?;
 
-            stdout.write_all(TWO.as_bytes()).await?;
-            stdout.write_all(b"\r").await?;
-            stdout.flush().await?;
+    if let Err(e) = tokio::fs::rename(&tmp, cache_file).await {
+        tokio::fs::remove_file(&tmp).await.ok();
+        return Err(e.into());
+    }
 
-            tokio::time::delay_for(Duration::from_millis(1000)).await;
+    Ok(())
+}
 
-            stdout.write_all(ONE.as_bytes()).await?;
-            stdout.write_all(" ".repeat(TWO.len() - ONE.len()).as_bytes()).await?;
-            stdout.write_all(b"\r").await?;
-            stdout.flush().await?;
+/// Replace the cache with `cache`. Failing to write the cache shouldn't fail the command that
+/// triggered it, so errors are only logged.
+async fn write_cache(cache_file: &Path, cache: &Cache) {
+    if let Err(e) = try_write_cache(cache_file, cache).await {
+        warn!("Couldn't update the speaker cache at {}: {}", cache_file.display(), e);
+    }
+}
 
-            tokio::time::delay_for(Duration::from_millis(999)).await;
+async fn read_cache(cache_file: &Path) -> Option<Cache> {
+    // a cache asked for by name belongs to some other household, the old one isn't its to inherit
+    if cache_file == default_cache_file() && !cache_file.exists() && Path::new(LEGACY_CACHE_FILE).exists() {
+        return migrate_legacy_cache(cache_file).await;
+    }
 
-            stdout.write_all(" ".repeat(TWO.len()).as_bytes()).await?;
-            stdout.write_all(b"\r").await?;
-            stdout.flush().await?;
+    // a cache somewhere read-only can still be read, just without the lock
+    let _lock = lock_cache(cache_file, false).await.ok();
+    let mut cache = tokio::fs::File::open(cache_file).await.ok()?;
 
-            Ok::<(), failure::Error>(())
-        });
+    let mut contents: Vec<u8> = vec![];
+    cache.read_to_end(&mut contents).await.ok()?;
+
+    // lists written before the cache kept its discovery time don't parse, they'll be replaced by
+    // the next discovery
+    serde_json::from_slice(contents.as_ref()).ok()
+}
+
+/// The cached speakers, `None` if there's no cache or it's past its TTL.
+async fn read_fresh_cache(cache_file: &Path) -> Option<Vec<CachedSpeaker>> {
+    read_cache(cache_file).await
+        .filter(Cache::is_fresh)
+        .map(|cache| cache.speakers)
+}
+
+/// The cache in `/tmp` holds either speakers or, from before it knew their names, bare IPs.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum LegacyCache {
+    Speakers(Vec<CachedSpeaker>),
+    Ips(Vec<IpAddr>),
+}
+
+/// Carry the old cache over so upgrading doesn't force a rediscovery.
+async fn migrate_legacy_cache(cache_file: &Path) -> Option<Cache> {
+    let contents = tokio::fs::read(LEGACY_CACHE_FILE).await.ok()?;
+
+    let speakers: Vec<CachedSpeaker> = match serde_json::from_slice(&contents).ok()? {
+        LegacyCache::Speakers(speakers) => speakers,
+        LegacyCache::Ips(ips) => join_all(ips.into_iter().map(Speaker::from_ip)).await
+            .iter()
+            .filter_map(|speaker| speaker.as_ref().ok())
+            .map(CachedSpeaker::from)
+            .collect(),
+    };
+
+    if speakers.is_empty() {
+        return None;
+    }
+
+    let cache = Cache::new(speakers, Vec::new());
+
+    // with nowhere writable to carry it to, the old cache is still better than nothing
+    if let Err(e) = try_write_cache(cache_file, &cache).await {
+        debug!("Couldn't move the speaker cache to {}, reading {} instead: {}", cache_file.display(), LEGACY_CACHE_FILE, e);
+    }
+
+    Some(cache)
+}
+
+/// Update the name of a speaker in the cache so lookups find it by its new name straight away.
+pub async fn rename_cached(cache_file: &Path, uuid: &str, name: &str) {
+    let _lock = lock_updates(cache_file).await.ok();
+    let mut cache = match read_cache(cache_file).await {
+        Some(cache) => cache,
+        None => return,
+    };
+
+    for speaker in cache.speakers.iter_mut().chain(&mut cache.manual).filter(|s| s.uuid == uuid) {
+        speaker.name = name.to_string();
+    }
+
+    write_cache(cache_file, &cache).await;
+}
+
+/// Add a speaker that discovery can't find (ie. on another subnet) to the cache by hand, so it
+/// can be found by name and is included in every discovery from now on.
+pub async fn add_manual(cache_file: &Path, speaker: &Speaker) -> Fallible<()> {
+    let _lock = lock_updates(cache_file).await.ok();
+    let mut cache = read_cache(cache_file).await.unwrap_or_default();
+    let entry = CachedSpeaker::from(speaker);
+
+    cache.manual.retain(|s| s.uuid != entry.uuid);
+    cache.manual.push(entry.clone());
+
+    cache.speakers.retain(|s| s.uuid != entry.uuid);
+    cache.speakers.push(entry);
+
+    try_write_cache(cache_file, &cache).await
+}
+
+/// Remove a speaker added with `add_manual`, by its room name or IP.
+pub async fn remove_manual(cache_file: &Path, identifier: &str) -> Fallible<CachedSpeaker> {
+    let _lock = lock_updates(cache_file).await.ok();
+    let mut cache = read_cache(cache_file).await.unwrap_or_default();
+
+    let matches = |s: &CachedSpeaker| s.name.eq_ignore_ascii_case(identifier.trim()) || s.ip.to_string() == identifier;
+
+    let i = match cache.manual.iter().position(matches) {
+        Some(i) => i,
+        None if cache.manual.is_empty() => bail!("{} isn't a manually added speaker, none have been added", identifier),
+        None => bail!(
+            "{} isn't a manually added speaker, manually added speakers are: {}",
+            identifier,
+            cache.manual.iter().map(|s| format!("{} ({})", s.name, s.ip)).collect::<Vec<_>>().join(", "),
+        ),
+    };
+
+    let removed = cache.manual.remove(i);
+    cache.speakers.retain(|s| s.uuid != removed.uuid);
+
+    try_write_cache(cache_file, &cache).await?;
+
+    Ok(removed)
+}
+
+/// Speakers added by hand with `add_manual`.
+pub async fn manual_speakers(cache_file: &Path) -> Vec<CachedSpeaker> {
+    read_cache(cache_file).await.map(|cache| cache.manual).unwrap_or_default()
+}
+
+/// Name, UUID and IP of every known speaker, straight from the cache if there is one so no
+/// speaker has to be contacted.
+pub async fn cached_speakers(cache_file: &Path, pretty: bool, invalidate: bool) -> Fallible<Vec<CachedSpeaker>> {
+    if !invalidate {
+        if let Some(cache) = read_fresh_cache(cache_file).await {
+            return Ok(cache);
+        }
     }
 
-    let speakers = sonos::discover().await?;
+    Ok(discover(cache_file, pretty, true).await?.iter().map(CachedSpeaker::from).collect())
+}
+
+pub async fn discover(cache_file: &Path, pretty: bool, invalidate: bool) -> Fallible<Vec<Speaker>> {
+    if !invalidate {
+        if let Some(mut cache) = read_cache(cache_file).await.filter(Cache::is_fresh) {
+            if STRICT_CACHE.load(Ordering::Relaxed) {
+                // if any of the cached speakers can't be reached the household has probably
+                // changed since, so fall through to a fresh discovery rather than failing
+                if let Ok(speakers) = try_join_all(cache.speakers.iter().map(|s| Speaker::from_ip(s.ip))).await {
+                    return Ok(speakers);
+                }
+            } else {
+                let speakers = join_all(cache.speakers.iter().map(|s| Speaker::from_ip(s.ip))).await;
+                let cached = cache.speakers.len();
+
+                // a speaker that's been unplugged (or whose IP has been handed to something else)
+                // shouldn't take every other speaker down with it, forget it and carry on
+                let speakers = cache.speakers.drain(..)
+                    .zip(speakers)
+                    .filter_map(|(cached, speaker)| speaker.ok().filter(|s| s.uuid == cached.uuid))
+                    .collect::<Vec<_>>();
+
+                if speakers.len() < cached {
+                    debug!("{} cached speakers couldn't be reached, removing them from the cache", cached - speakers.len());
+
+                    cache.speakers = speakers.iter().map(CachedSpeaker::from).collect();
+                    write_cache(cache_file, &cache).await;
+                }
+
+                if !speakers.is_empty() {
+                    return Ok(speakers);
+                }
+            }
+        }
+    }
+
+    // a cache somewhere read-only, or a lock that was never let go of, just means going without it
+    let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (_lock, waited) = match lock_updates(cache_file).await {
+        Ok((lock, waited)) => (Some(lock), waited),
+        Err(_) => (None, false),
+    };
+
+    // whoever had the lock was discovering too, their results are as fresh as ours would be
+    if let Some(cache) = read_cache(cache_file).await.filter(|cache| waited && cache.discovered >= started) {
+        let speakers = join_all(cache.speakers.iter().map(|s| Speaker::from_ip(s.ip))).await
+            .into_iter()
+            .filter_map(Result::ok)
+            .collect::<Vec<_>>();
+
+        if !speakers.is_empty() {
+            return Ok(speakers);
+        }
+    }
+
+    let timeout = self::timeout();
+    let rounds = DISCOVERY_ROUNDS.load(Ordering::Relaxed);
 
-    {
-        // write IP addresses of all known speakers to cache
-        let writer = std::fs::File::create(CACHE_FILE_NAME).unwrap();
-        let mut serializer = serde_json::Serializer::new(writer);
+    let manual = read_cache(cache_file).await.map(|cache| cache.manual).unwrap_or_default();
 
-        speakers.iter()
-            .map(|s| s.ip)
-            .collect::<Vec<IpAddr>>()
-            .serialize(&mut serializer).unwrap();
+    let search = async {
+        let mut ips = util::timed("ssdp search", ssdp(timeout, rounds)).await?;
+
+        for ip in util::timed("scan", scan()).await?.into_iter().chain(manual.iter().map(|s| s.ip)) {
+            if !ips.contains(&ip) {
+                ips.push(ip);
+            }
+        }
+
+        // speakers that answered the search but can't be reached are left out, and a speaker
+        // that answered from more than one address is only kept once
+        let mut speakers = Vec::<Speaker>::new();
+
+        for speaker in join_all(ips.into_iter().map(Speaker::from_ip)).await.into_iter().filter_map(Result::ok) {
+            if !speakers.iter().any(|s| s.uuid == speaker.uuid) {
+                speakers.push(speaker);
+            }
+        }
+
+        Ok::<_, failure::Error>(speakers)
+    };
+
+    let speakers = if pretty && SHOW_PROGRESS.load(Ordering::Relaxed) && atty::is(atty::Stream::Stderr) {
+        let longest = std::cell::Cell::new(0);
+        let countdown = countdown(timeout, rounds, &longest);
+        futures::pin_mut!(search, countdown);
+
+        // the countdown only stops early if stderr goes away, keep searching regardless
+        let speakers = match futures::future::select(search, countdown).await {
+            Either::Left((speakers, _)) => speakers,
+            Either::Right((_, search)) => search.await,
+        };
+
+        eprint!("{:1$}\r", "", longest.get());
+
+        speakers?
+    } else {
+        search.await?
+    };
+
+    if speakers.is_empty() {
+        return Err(not_found!("Couldn't find any speakers after {} search{}, try a longer --discovery-timeout or --scan", rounds, if rounds == 1 { "" } else { "es" }));
     }
 
+    // write the name, UUID and IP address of all known speakers to cache
+    write_cache(cache_file, &Cache::new(speakers.iter().map(CachedSpeaker::from).collect(), manual)).await;
+
     Ok(speakers)
 }
+
+/// Count down the discovery timeout on stderr until cancelled, keeping track of the longest line
+/// written so the caller can clear it.
+async fn countdown(timeout: Duration, rounds: u64, longest: &std::cell::Cell<usize>) -> Fallible<()> {
+    use std::io::Write;
+
+    const TICK: Duration = Duration::from_millis(250);
+
+    let mut stderr = std::io::stderr();
+    let start = tokio::time::Instant::now();
+
+    loop {
+        let elapsed = start.elapsed().min(timeout);
+        let remaining = (timeout - elapsed).as_millis().div_ceil(1000).max(1);
+        let round = (elapsed.as_millis() * u128::from(rounds) / timeout.as_millis() + 1).min(u128::from(rounds));
+
+        let mut message = if remaining == 1 {
+            format!("{}Give me a sec to discover your devices", util::glyph(util::Glyph::Waiting))
+        } else {
+            format!("{}Give me {} secs to discover your devices", util::glyph(util::Glyph::Waiting), remaining)
+        };
+
+        if rounds > 1 {
+            message.push_str(&format!(" (search {}/{})", round, rounds));
+        }
+
+        message.push_str("...");
+
+        longest.set(longest.get().max(message.len()));
+
+        write!(stderr, "{:1$}\r", message, longest.get())?;
+        stderr.flush()?;
+
+        tokio::time::delay_for(TICK).await;
+    }
+}
+
+/// Multicast `rounds` SSDP searches for Sonos speakers spread across `timeout`, collecting the
+/// addresses of everything that answers any of them. Searches go out over both IPv4 and IPv6,
+/// networks without IPv6 just won't get any answers to the latter.
+async fn ssdp(timeout: Duration, rounds: u64) -> Fallible<Vec<IpAddr>> {
+    let bind = *BIND_ADDRESS.lock().expect("bind address poisoned");
+
+    let v4 = ssdp_v4(bind.unwrap_or(Ipv4Addr::UNSPECIFIED), timeout, rounds);
+
+    // --bind and --interface pick an IPv4 address, searching over IPv6 as well would go out of
+    // whichever interface the OS prefers
+    let v6 = async {
+        match bind {
+            Some(_) => Ok(Vec::new()),
+            None => ssdp_v6(timeout, rounds).await,
+        }
+    };
+
+    let (v4, v6) = futures::join!(v4, v6);
+
+    let v6 = v6.unwrap_or_else(|e| {
+        debug!("IPv6 SSDP search failed: {}", e);
+        Vec::new()
+    });
+
+    let mut ips = match v4 {
+        Ok(ips) => ips,
+        Err(e) if v6.is_empty() => return Err(e),
+        Err(e) => {
+            debug!("IPv4 SSDP search failed: {}", e);
+            Vec::new()
+        },
+    };
+
+    ips.extend(v6.into_iter().filter(|ip| !ips.contains(ip)).collect::<Vec<_>>());
+
+    Ok(ips)
+}
+
+async fn ssdp_v4(bind: Ipv4Addr, timeout: Duration, rounds: u64) -> Fallible<Vec<IpAddr>> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv4(), socket2::Type::dgram(), Some(socket2::Protocol::udp()))?;
+
+    if !bind.is_unspecified() {
+        // binding alone doesn't pick the interface multicast goes out of
+        socket.set_multicast_if_v4(&bind)?;
+    }
+
+    socket.bind(&std::net::SocketAddr::from((bind, 0)).into())?;
+
+    search(socket, &["239.255.255.250:1900"], timeout, rounds).await
+}
+
+async fn ssdp_v6(timeout: Duration, rounds: u64) -> Fallible<Vec<IpAddr>> {
+    let socket = socket2::Socket::new(socket2::Domain::ipv6(), socket2::Type::dgram(), Some(socket2::Protocol::udp()))?;
+    socket.set_only_v6(true)?;
+    socket.bind(&std::net::SocketAddr::from((std::net::Ipv6Addr::UNSPECIFIED, 0)).into())?;
+
+    let ips = search(socket, &["[ff05::c]:1900", "[ff02::c]:1900"], timeout, rounds).await?;
+
+    // link-local addresses are useless without knowing which interface they're on, speakers
+    // answering the site-local search do so from a routable address as well
+    Ok(ips.into_iter()
+        .filter(|ip| !matches!(ip, IpAddr::V6(v6) if v6.segments()[0] & 0xffc0 == 0xfe80))
+        .collect())
+}
+
+/// Send the search for Sonos speakers to each of the multicast `groups` once per round and
+/// gather the addresses that answer.
+async fn search(socket: socket2::Socket, groups: &[&str], timeout: Duration, rounds: u64) -> Fallible<Vec<IpAddr>> {
+    socket.set_nonblocking(true)?;
+
+    let mut socket = tokio::net::UdpSocket::from_std(socket.into_udp_socket())?;
+
+    let start = tokio::time::Instant::now();
+    let mut buf = [0; 2048];
+    let mut ips = Vec::new();
+
+    for round in 0..rounds {
+        for group in groups {
+            let search = format!(
+                "M-SEARCH * HTTP/1.1\r\n\
+                HOST: {}\r\n\
+                MAN: \"ssdp:discover\"\r\n\
+                MX: 1\r\n\
+                ST: urn:schemas-upnp-org:device:ZonePlayer:1\r\n\r\n",
+                group.to_uppercase(),
+            );
+
+            socket.send_to(search.as_bytes(), group).await?;
+        }
+
+        // keep listening for answers to every search sent so far until the next one is due
+        let next = start + timeout * (round + 1) as u32 / rounds as u32;
+
+        while let Ok(received) = tokio::time::timeout_at(next, socket.recv_from(&mut buf)).await {
+            let (len, addr) = received?;
+
+            if String::from_utf8_lossy(&buf[..len]).contains("ZonePlayer") && !ips.contains(&addr.ip()) {
+                ips.push(addr.ip());
+            }
+        }
+
+        debug!("SSDP search {}/{} found {} speakers so far", round + 1, rounds, ips.len());
+    }
+
+    Ok(ips)
+}
+
+/// Probe every address in the `--scan` ranges for a Sonos device description, returning the
+/// addresses of the speakers that answered.
+async fn scan() -> Fallible<Vec<IpAddr>> {
+    use futures::stream::StreamExt;
+
+    const MAX_IN_FLIGHT: usize = 64;
+    const ZONE_PLAYER: &str = "urn:schemas-upnp-org:device:ZonePlayer:1";
+
+    let ranges = SCAN_RANGES.lock().expect("scan ranges poisoned").clone();
+
+    if ranges.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_millis(750))
+        .build()?;
+
+    let hosts = ranges.into_iter().flat_map(|(network, prefix)| {
+        let mask = u32::MAX.checked_shl(32 - u32::from(prefix)).unwrap_or(0);
+        let first = u32::from(network) & mask;
+        let last = first | !mask;
+
+        // skip the network and broadcast addresses unless the range is too small to have them
+        let (first, last) = if prefix < 31 { (first + 1, last - 1) } else { (first, last) };
+
+        (first..=last).map(|ip| IpAddr::V4(Ipv4Addr::from(ip)))
+    });
+
+    let client = &client;
+
+    let found = futures::stream::iter(hosts)
+        .map(|ip| async move {
+            let response = client.get(&format!("{}/xml/device_description.xml", crate::upnp::base_url(ip))).send().await.ok()?;
+
+            // plenty of other things listen on 1400, make sure it's actually a speaker
+            if response.text().await.ok()?.contains(ZONE_PLAYER) {
+                Some(ip)
+            } else {
+                None
+            }
+        })
+        .buffer_unordered(MAX_IN_FLIGHT)
+        .filter_map(|ip| async move { ip })
+        .collect()
+        .await;
+
+    Ok(found)
+}
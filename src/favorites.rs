@@ -0,0 +1,77 @@
+use sonos::Speaker;
+use failure::Fallible;
+
+use crate::upnp::{self, Service};
+use crate::{queue, transport, util};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Favorite {
+    pub title: String,
+    pub uri: String,
+    /// DIDL-Lite metadata the favorite was saved with, needed to play most services' content
+    #[serde(skip)]
+    pub metadata: String,
+}
+
+/// Every item in the household's Sonos Favorites.
+pub async fn list(speaker: &Speaker) -> Fallible<Vec<Favorite>> {
+    let response = upnp::call(speaker.ip, Service::ContentDirectory, "Browse", &[
+        ("ObjectID", "FV:2"),
+        ("BrowseFlag", "BrowseDirectChildren"),
+        ("Filter", "*"),
+        ("StartingIndex", "0"),
+        ("RequestedCount", "1000"),
+        ("SortCriteria", ""),
+    ]).await?;
+
+    let didl = upnp::child_text(&response, "Result").unwrap_or_default();
+
+    Ok(upnp::didl_items(&didl)?
+        .iter()
+        .map(|item| Favorite {
+            title: upnp::child_text(item, "title").unwrap_or_default(),
+            uri: upnp::child_text(item, "res").unwrap_or_default(),
+            metadata: upnp::child_text(item, "resMD").unwrap_or_default(),
+        })
+        .collect())
+}
+
+/// The favorite whose title best matches `name`, asking before going with a rough match.
+pub async fn find(favorites: &[Favorite], name: &str) -> Fallible<Favorite> {
+    let found = util::fuzzy_match(favorites, name, |f| &f.title);
+
+    match (found.confidence, found.matches.as_slice()) {
+        (util::Confidence::None, _) | (_, []) => Err(not_found!("Couldn't find a favorite by that name")),
+        (util::Confidence::Fuzzy, [favorite]) => {
+            if !util::confirm(&format!("Couldn't find favorite '{}', did you mean {}?", name, favorite.title)).await? {
+                return Err(not_found!("Couldn't find a favorite by that name"));
+            }
+
+            Ok((*favorite).clone())
+        },
+        (_, [favorite]) => Ok((*favorite).clone()),
+        (_, favorites) => bail!(
+            "'{}' matches more than one favorite: {}",
+            name,
+            favorites.iter().map(|f| f.title.as_str()).collect::<Vec<_>>().join(", "),
+        ),
+    }
+}
+
+/// Start playing a favorite. Playlists and albums are loaded into the queue, everything else
+/// (radio stations, mostly) is played directly.
+pub async fn play(speaker: &Speaker, favorite: &Favorite) -> Fallible<()> {
+    let container = ["x-rincon-cpcontainer:", "x-rincon-playlist:", "file:///jffs/settings/savedqueues.rsq"]
+        .iter()
+        .any(|prefix| favorite.uri.starts_with(prefix));
+
+    if container {
+        queue::replace(speaker, std::iter::once((favorite.uri.as_str(), favorite.metadata.as_str()))).await?;
+        queue::use_queue(speaker).await?;
+        transport::skip_to(speaker.ip, 1).await?;
+    } else {
+        transport::set_uri(speaker.ip, &favorite.uri, &favorite.metadata).await?;
+    }
+
+    transport::play(speaker.ip).await
+}
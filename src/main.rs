@@ -4,15 +4,30 @@
 #[macro_use] extern crate failure;
 
 #[macro_use] mod util;
+mod alarms;
+mod config;
+mod didl;
 mod discovery;
+mod favorites;
+mod probe;
+mod queue;
+mod serve;
+mod snapshot;
+mod status;
+mod topology;
+mod transport;
+mod upnp;
+mod volume;
 
+use std::convert::TryFrom;
 use std::time::Duration;
 use std::net::IpAddr;
 
-use sonos::Speaker;
+use sonos::{Speaker, TransportState};
 
 use failure::Fallible;
-use crate::discovery::find_speaker_by_name;
+
+use crate::util::Glyph;
 
 fn argparse<'a, 'b>() -> clap::App<'a, 'b> {
     use clap::{App, AppSettings, Arg, SubCommand};
@@ -23,34 +38,492 @@ fn argparse<'a, 'b>() -> clap::App<'a, 'b> {
         .about("Control your Sonos using the command line")
         .setting(AppSettings::SubcommandRequired)
         .arg(Arg::with_name("controller")
-                .help("Set the controller to run operation on")
+                .help("Set the controller to run operation on by IP, room name or UUID, can be given multiple times for play, pause, stop, volume, seek, track next/prev, ungroup and group leave. Defaults to $SONOS_CONTROLLER or $SONOS_ROOM")
                 .short("c")
-                .required_unless("rooms")
-                .value_name("IP or Room Name")
-                .takes_value(true))
+                .value_name("IP, Room Name or UUID")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("uuid")
+                .help("Set the controller to run operation on by its UUID (RINCON_...), which stays the same when names and IPs change")
+                .long("uuid")
+                .alias("controller-uuid")
+                .value_name("UUID")
+                .takes_value(true)
+                .conflicts_with_all(&["controller", "all"]))
+        .arg(Arg::with_name("all")
+                .help("Run the operation against every room in the household, asking first unless --yes is given")
+                .long("all")
+                .conflicts_with("controller"))
         .arg(Arg::with_name("json")
-                .help("Return back JSON serialised responses for programmatic use of the CLI"))
-        .subcommand(SubCommand::with_name("info").about("Shows information about the speaker"))
+                .help("Return back JSON serialised responses for programmatic use of the CLI")
+                .long("json")
+                .short("j")
+                .global(true))
+        .arg(Arg::with_name("no-json")
+                .help("Print text output even when the config file asks for JSON")
+                .long("no-json")
+                .conflicts_with("json")
+                .global(true))
+        .arg(Arg::with_name("dry-run")
+                .help("Print the UPnP actions that would change something instead of sending them, reads still go to the speakers")
+                .long("dry-run")
+                .global(true))
+        .arg(Arg::with_name("ascii")
+                .help("Use plain text labels instead of emoji and box drawing characters, the default when the locale isn't UTF-8")
+                .long("ascii")
+                .alias("no-emoji")
+                .global(true))
+        .arg(Arg::with_name("color")
+                .help("When to color output, auto only colors a terminal and respects $NO_COLOR")
+                .long("color")
+                .value_name("WHEN")
+                .possible_values(&["auto", "always", "never"])
+                .global(true)
+                .takes_value(true))
+        .arg(Arg::with_name("pretty")
+                .help("Indent --json output so it's easier to read")
+                .long("pretty")
+                .global(true))
+        .arg(Arg::with_name("output-format")
+                .help("Output results as rows of tab or comma separated values, with durations in seconds")
+                .long("output-format")
+                .value_name("FORMAT")
+                .possible_values(&["tsv", "csv"])
+                .conflicts_with("json")
+                .global(true)
+                .takes_value(true))
+        .arg(Arg::with_name("no-header")
+                .help("Leave the header row off --output-format output")
+                .long("no-header")
+                .requires("output-format")
+                .global(true))
+        .arg(Arg::with_name("output")
+                .help("Write results to this file instead of stdout, overwriting it unless --append is given")
+                .long("output")
+                .short("o")
+                .value_name("FILE")
+                .takes_value(true))
+        .arg(Arg::with_name("append")
+                .help("Append results to the --output file rather than overwriting it")
+                .long("append")
+                .requires("output"))
+        .arg(Arg::with_name("raw-seconds")
+                .help("Output durations in JSON as whole seconds")
+                .long("raw-seconds"))
+        .arg(Arg::with_name("no-redirect")
+                .help("Send transport and queue commands to the given speaker even if it isn't its group's coordinator")
+                .long("no-redirect"))
+        .arg(Arg::with_name("retry-on-group-change")
+                .help("If a transport command fails because the group's coordinator changed, run it again on the new coordinator")
+                .long("retry-on-group-change")
+                .conflicts_with("no-redirect"))
+        .arg(Arg::with_name("verbose")
+                .help("Log what's going on behind the scenes to stderr, ie. every request sent to a speaker")
+                .long("verbose")
+                .short("v"))
+        .arg(Arg::with_name("quiet")
+                .help("Print nothing on success, not even the result unless --json is given. Errors are still printed and reflected in the exit code")
+                .long("quiet")
+                .short("q")
+                .global(true))
+        .arg(Arg::with_name("cache-file")
+                .help("Keep the speaker cache in this file instead, ie. one per household. Defaults to $SONOS_CACHE_FILE, or the older $SONOS_CLI_CACHE, if either is set")
+                .long("cache-file")
+                .value_name("FILE")
+                .global(true)
+                .takes_value(true))
+        .arg(Arg::with_name("discovery-timeout")
+                .help("Seconds to wait for speakers to respond when discovering them, 1-30")
+                .long("discovery-timeout")
+                .value_name("SECS")
+                .default_value("2")
+                .takes_value(true))
+        .arg(Arg::with_name("discovery-rounds")
+                .help("Number of searches to send while discovering, spread across the discovery timeout")
+                .long("discovery-rounds")
+                .value_name("N")
+                .default_value("3")
+                .takes_value(true))
+        .arg(Arg::with_name("scan")
+                .help("Also probe every address in this range for speakers when discovering, for networks that block SSDP multicast")
+                .long("scan")
+                .value_name("CIDR")
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1))
+        .arg(Arg::with_name("interface")
+                .help("Network interface to send the discovery search out of, eg. eth1")
+                .long("interface")
+                .value_name("NAME")
+                .takes_value(true))
+        .arg(Arg::with_name("bind")
+                .help("Local address to send the discovery search from")
+                .long("bind")
+                .value_name("IP")
+                .takes_value(true)
+                .conflicts_with("interface"))
+        .arg(Arg::with_name("cache-ttl")
+                .help("Hours before the speaker cache is considered stale and speakers are discovered again")
+                .long("cache-ttl")
+                .value_name("HOURS")
+                .default_value("24")
+                .takes_value(true))
+        .arg(Arg::with_name("strict-cache")
+                .help("Discover speakers again if any cached speaker can't be reached, instead of dropping it from the cache")
+                .long("strict-cache"))
+        .arg(Arg::with_name("yes")
+                .help("Answer yes to any prompts, ie. when a room name only roughly matches a speaker")
+                .long("yes")
+                .short("y"))
+        .arg(Arg::with_name("no-input")
+                .help("Never prompt, fail instead when a prompt would be needed")
+                .long("no-input"))
+        .arg(Arg::with_name("timing")
+                .help("Print how long resolving the speaker, discovery and the whole command took to stderr")
+                .long("timing"))
+        .arg(Arg::with_name("retries")
+                .help("Number of times to retry reads and safe writes that fail with a transient network error")
+                .long("retries")
+                .value_name("N")
+                .default_value("2")
+                .takes_value(true))
+        .arg(Arg::with_name("timeout")
+                .help("Seconds to wait for each speaker operation before giving up, finding speakers isn't included")
+                .long("timeout")
+                .value_name("SECS")
+                .default_value("5")
+                .takes_value(true))
+        .subcommand(SubCommand::with_name("play").about("Resume playback")
+                        .arg(Arg::with_name("wait").long("wait").help("Don't return until playback has actually started, up to --timeout")))
+        .subcommand(SubCommand::with_name("pause").about("Pause playback"))
+        .subcommand(SubCommand::with_name("stop").about("Stop playback"))
+        .subcommand(SubCommand::with_name("info").about("Shows information about the speaker")
+                        .arg(Arg::with_name("network")
+                                .help("Include the speaker's network connection details")
+                                .long("network"))
+                        .arg(format_arg(Info::FIELDS)))
+        .subcommand(SubCommand::with_name("find").about("Print the IP address of a speaker by its room name, or all of its details with --json")
+                        .arg(Arg::with_name("NAME")
+                                .help("Room name, UUID or alias of the speaker")
+                                .required(true)
+                                .index(1)))
+        .subcommand(
+            SubCommand::with_name("config")
+                .about("Commands to inspect the config file")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("path").about("Print where the config file is read from"))
+                .subcommand(SubCommand::with_name("show").about("Print the settings in effect, after flags and the environment are taken into account"))
+        )
+        .subcommand(SubCommand::with_name("status").about("Shows everything about the speaker at once: what's playing, its volume, play mode and group")
+                        .arg(Arg::with_name("oneline")
+                                .help("Print what's playing and the volume on a single line, for status bars like polybar and i3blocks")
+                                .long("oneline"))
+                        .arg(Arg::with_name("waybar")
+                                .help("Print a JSON object for a Waybar custom module, with a class of playing, paused, stopped or error to style it by")
+                                .long("waybar")
+                                .conflicts_with("oneline"))
+                        .arg(Arg::with_name("max-width")
+                                .help("Shorten the artist and title so the --oneline output is at most this many characters")
+                                .long("max-width")
+                                .value_name("CHARS")
+                                .takes_value(true)
+                                .requires("oneline")))
+        .subcommand(SubCommand::with_name("clock").about("Shows the speaker's current time and time zone, which alarms go off by"))
+        .subcommand(SubCommand::with_name("battery").about("Shows the battery level of portable speakers"))
+        .subcommand(SubCommand::with_name("diagnostics").about("Shows the speaker's uptime, signal strength and battery status"))
         .subcommand(
             SubCommand::with_name("track")
                 .about("Commands to manipulate the tracklist")
-                .subcommand(SubCommand::with_name("next").about("Skip to the next track"))
-                .subcommand(SubCommand::with_name("prev").about("Go back to the last track"))
-                .subcommand(SubCommand::with_name("list").about("Get the list of tracks in the queue"))
+                .arg(format_arg(Track::FIELDS))
+                .subcommand(SubCommand::with_name("next").about("Skip to the next track")
+                                .arg(Arg::with_name("wait").long("wait").help("Don't return until the speaker has finished changing tracks, up to --timeout")))
+                .subcommand(SubCommand::with_name("prev").about("Go back to the last track")
+                                .arg(Arg::with_name("wait").long("wait").help("Don't return until the speaker has finished changing tracks, up to --timeout")))
+                .subcommand(SubCommand::with_name("current").about("Get the queue position of the current track"))
+                .subcommand(
+                    SubCommand::with_name("list")
+                        .about("Get the list of tracks in the queue")
+                        .arg(Arg::with_name("artist").long("artist").takes_value(true).help("Only show tracks whose artist contains the given text"))
+                        .arg(Arg::with_name("album").long("album").takes_value(true).help("Only show tracks whose album contains the given text"))
+                        .arg(Arg::with_name("title").long("title").takes_value(true).help("Only show tracks whose title contains the given text"))
+                        .arg(Arg::with_name("uris").long("uris").help("Include each track's URI"))
+                        .arg(Arg::with_name("table").long("table").help("Line the tracks up in columns under a header"))
+                        .arg(Arg::with_name("columns")
+                                .long("columns")
+                                .value_name("COLUMNS")
+                                .takes_value(true)
+                                .requires("table")
+                                .help("Comma separated columns to show with --table, out of pos, title, artist, album, duration and uri"))
+                )
                 .subcommand(
                     SubCommand::with_name("play")
                         .about("Play a given track")
                         .subcommand(SubCommand::with_name("tv").about("Set the current speaker's input to the SPDIF"))
-                        .subcommand(SubCommand::with_name("line-in").about("Set the current speaker's input to the line-in"))
+                        .subcommand(SubCommand::with_name("line-in").about("Set the current speaker's input to the line-in")
+                                        .arg(Arg::with_name("from")
+                                                .help("Play the line-in of another room instead")
+                                                .long("from")
+                                                .value_name("ROOM")
+                                                .takes_value(true)))
                         .arg(Arg::with_name("uri").help("Queue position to skip to or a Sonos URI to play").index(1).conflicts_with_all(&["tv", "line-in"]))
+                        .arg(Arg::with_name("append")
+                                .help("Add the URI to the end of the queue instead of replacing what's playing")
+                                .long("append")
+                                .alias("enqueue")
+                                .requires("uri"))
+                        .arg(Arg::with_name("title").long("title").takes_value(true).requires("uri").help("Title to send as metadata with the URI"))
+                        .arg(Arg::with_name("artist").long("artist").takes_value(true).requires("uri").help("Artist to send as metadata with the URI"))
+                        .arg(Arg::with_name("album").long("album").takes_value(true).requires("uri").help("Album to send as metadata with the URI"))
+                        .arg(Arg::with_name("art").long("art").takes_value(true).requires("uri").help("Album art URL to send as metadata with the URI"))
+                        .arg(Arg::with_name("wait").long("wait").help("Don't return until playback has actually started, up to --timeout"))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("play-radio")
+                .about("Play a TuneIn radio station")
+                .arg(Arg::with_name("STATION")
+                        .help("TuneIn station ID, eg. s24940")
+                        .required(true)
+                        .index(1))
+                .arg(Arg::with_name("title")
+                        .help("Name of the station to show while it's playing")
+                        .long("title")
+                        .takes_value(true))
+        )
+        .subcommand(
+            SubCommand::with_name("play-url")
+                .about("Play an audio file or stream from a URL, working out how the speaker needs to be told to play it")
+                .arg(Arg::with_name("URL")
+                        .help("http(s) URL of the file or stream")
+                        .required(true)
+                        .index(1))
+                .arg(Arg::with_name("title")
+                        .help("Title to show while it's playing, defaults to the stream's name or the file's name")
+                        .long("title")
+                        .takes_value(true))
+        )
+        .subcommand(
+            SubCommand::with_name("play-file")
+                .about("Play a file from this computer, serving it to the speaker until it finishes playing")
+                .arg(Arg::with_name("FILE")
+                        .help("Path to the audio file to play")
+                        .required(true)
+                        .index(1))
+        )
+        .subcommand(
+            SubCommand::with_name("favorite")
+                .about("Play one of your Sonos Favorites by name, or list them")
+                .arg(Arg::with_name("NAME")
+                        .help("Name of the favorite to play, doesn't have to be exact")
+                        .index(1))
+        )
+        .subcommand(
+            SubCommand::with_name("queue")
+                .about("Commands to manage the queue")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add a URI to the queue")
+                        .arg(Arg::with_name("uri").help("Sonos URI to enqueue").required(true).index(1))
+                        .arg(Arg::with_name("at")
+                                .help("Queue position to insert the item at, appends to the end by default")
+                                .long("at")
+                                .value_name("POSITION")
+                                .takes_value(true))
+                )
+                .subcommand(
+                    SubCommand::with_name("play-next")
+                        .about("Add a URI straight after the current track so it plays next")
+                        .alias("insert-next")
+                        .arg(Arg::with_name("uri").help("Sonos URI to enqueue").required(true).index(1))
                 )
         )
-        .subcommand(SubCommand::with_name("group").about("Group this speaker with the given master")
-                        .arg(Arg::with_name("MASTER")
-                                .help("Name of the speaker to group with")
+        .subcommand(
+            SubCommand::with_name("group")
+                .about("Commands to manage the speaker's group")
+                .setting(AppSettings::ArgRequiredElseHelp)
+                .setting(AppSettings::ArgsNegateSubcommands)
+                .arg(Arg::with_name("MASTER")
+                        .help("Name of the speaker to group with, shorthand for `group join`")
+                        .index(1))
+                .subcommand(
+                    SubCommand::with_name("join")
+                        .about("Join this speaker to the group of the given room")
+                        .arg(Arg::with_name("ROOM")
+                                .help("IP or name of a speaker in the group to join")
                                 .required(true)
-                                .index(1)))
+                                .index(1))
+                )
+                .subcommand(SubCommand::with_name("leave").about("Take this speaker out of its group"))
+                .subcommand(
+                    SubCommand::with_name("all")
+                        .about("Join every speaker in the household to this speaker's group")
+                        .arg(Arg::with_name("except")
+                                .help("Comma separated list of rooms to leave out")
+                                .long("except")
+                                .value_name("ROOMS")
+                                .takes_value(true)
+                                .use_delimiter(true))
+                )
+        )
         .subcommand(SubCommand::with_name("ungroup").about("Ungroup this speaker from the master"))
+        .subcommand(
+            SubCommand::with_name("satellites")
+                .about("Commands to manage the surrounds bonded to a soundbar")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Bond a pair of surrounds to this soundbar")
+                        .arg(Arg::with_name("LEFT")
+                                .help("IP or name of the speaker to use as the left surround")
+                                .required(true)
+                                .index(1))
+                        .arg(Arg::with_name("RIGHT")
+                                .help("IP or name of the speaker to use as the right surround")
+                                .required(true)
+                                .index(2))
+                )
+                .subcommand(SubCommand::with_name("remove").about("Unbond the surrounds from this soundbar"))
+        )
+        .subcommand(
+            SubCommand::with_name("pair")
+                .about("Commands to manage stereo pairs")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Bond two speakers of the same model into a stereo pair")
+                        .arg(Arg::with_name("SPEAKERS")
+                                .help("Left=<IP or Room Name> Right=<IP or Room Name>")
+                                .required(true)
+                                .multiple(true)
+                                .number_of_values(2)
+                                .index(1))
+                )
+                .subcommand(
+                    SubCommand::with_name("separate")
+                        .about("Split a stereo pair back into two rooms")
+                        .arg(Arg::with_name("ROOM")
+                                .help("IP or name of the paired room")
+                                .required(true)
+                                .index(1))
+                )
+        )
+        .subcommand(SubCommand::with_name("ungroup-all").about("Split every group so each room plays on its own")
+                        .arg(Arg::with_name("room")
+                                .help("Only split the group containing this room")
+                                .long("room")
+                                .value_name("ROOM")
+                                .takes_value(true)))
+        .subcommand(SubCommand::with_name("transfer").about("Move playback and the queue from one room to another")
+                        .arg(Arg::with_name("FROM")
+                                .help("IP or name of the room to move playback from")
+                                .required(true)
+                                .index(1))
+                        .arg(Arg::with_name("TO")
+                                .help("IP or name of the room to move playback to")
+                                .required(true)
+                                .index(2))
+                        .arg(Arg::with_name("force")
+                                .help("Take the destination room out of its group if it's grouped with other rooms")
+                                .long("force")))
+        .subcommand(
+            SubCommand::with_name("snapshot")
+                .about("Save and restore the grouping, playback and volume of every room")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("save")
+                        .about("Capture the current state of every room")
+                        .arg(Arg::with_name("NAME").help("Name to save the snapshot as").required(true).index(1))
+                )
+                .subcommand(
+                    SubCommand::with_name("restore")
+                        .about("Put every room back the way it was when the snapshot was taken")
+                        .arg(Arg::with_name("NAME").help("Name of the snapshot to restore").required(true).index(1))
+                )
+                .subcommand(SubCommand::with_name("list").about("List saved snapshots"))
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete a saved snapshot")
+                        .arg(Arg::with_name("NAME").help("Name of the snapshot to delete").required(true).index(1))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("alarms")
+                .about("Manage the household's alarms")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(SubCommand::with_name("list").about("List every alarm, or only the -c room's alarms"))
+                .subcommand(
+                    SubCommand::with_name("create")
+                        .about("Create a new alarm, in the -c room unless --room is given")
+                        .arg(Arg::with_name("room").long("room").value_name("IP, Room Name or UUID").takes_value(true).help("Room the alarm should play in"))
+                        .arg(Arg::with_name("time").long("time").value_name("HH:MM").takes_value(true).required(true).help("Time of day the alarm goes off"))
+                        .arg(Arg::with_name("recurrence")
+                                .long("recurrence")
+                                .takes_value(true)
+                                .default_value("DAILY")
+                                .help("ONCE, DAILY, WEEKDAYS, WEEKENDS or ON_ followed by day numbers, eg. ON_135 for Monday, Wednesday and Friday (0 is Sunday)"))
+                        .arg(Arg::with_name("volume").long("volume").takes_value(true).default_value("20").help("Volume to play the alarm at, 0-100"))
+                        .arg(Arg::with_name("duration").long("duration").value_name("HH:MM:SS").takes_value(true).default_value("01:00:00").help("How long the alarm plays for before stopping"))
+                        .arg(Arg::with_name("uri").long("uri").takes_value(true).help("Sonos URI to play, the Sonos chime by default"))
+                        .arg(Arg::with_name("title").long("title").takes_value(true).requires("uri").help("Title to send as metadata with the URI"))
+                        .arg(Arg::with_name("play-mode")
+                                .long("play-mode")
+                                .takes_value(true)
+                                .default_value("NORMAL")
+                                .possible_values(&["NORMAL", "REPEAT_ALL", "SHUFFLE", "SHUFFLE_NOREPEAT"])
+                                .help("Play mode to use for the URI"))
+                        .arg(Arg::with_name("include-grouped").long("include-grouped").help("Also play the alarm in the rooms grouped with the alarm's room"))
+                        .arg(Arg::with_name("disabled").long("disabled").help("Create the alarm switched off"))
+                )
+                .subcommand(
+                    SubCommand::with_name("delete")
+                        .about("Delete an alarm, asking first unless --yes is given")
+                        .arg(Arg::with_name("ID").help("ID of the alarm, as shown by alarms list").required(true).index(1))
+                )
+                .subcommand(
+                    SubCommand::with_name("enable")
+                        .about("Switch an alarm back on")
+                        .arg(Arg::with_name("ID").help("ID of the alarm, as shown by alarms list").required(true).index(1))
+                )
+                .subcommand(
+                    SubCommand::with_name("disable")
+                        .about("Switch an alarm off without deleting it")
+                        .arg(Arg::with_name("ID").help("ID of the alarm, as shown by alarms list").required(true).index(1))
+                )
+        )
+        .subcommand(
+            SubCommand::with_name("use")
+                .about("Set the controller commands run on when -c isn't given, or show it")
+                .arg(Arg::with_name("ROOM")
+                        .help("IP, name or UUID of the room to use from now on")
+                        .index(1))
+                .arg(Arg::with_name("clear")
+                        .help("Stop using a default controller")
+                        .long("clear")
+                        .conflicts_with("ROOM"))
+        )
+        .subcommand(
+            SubCommand::with_name("speaker")
+                .about("Manage speakers added by hand, for speakers discovery can't reach such as those on another subnet")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(
+                    SubCommand::with_name("add")
+                        .about("Add the speaker at the given IP so it can be used by name")
+                        .arg(Arg::with_name("IP").help("IP address of the speaker").required(true).index(1))
+                )
+                .subcommand(
+                    SubCommand::with_name("remove")
+                        .about("Forget a speaker added by hand")
+                        .arg(Arg::with_name("SPEAKER").help("Room name or IP of the speaker").required(true).index(1))
+                )
+                .subcommand(SubCommand::with_name("list").about("List the speakers added by hand"))
+        )
+        .subcommand(SubCommand::with_name("rename").about("Rename the speaker's room")
+                        .arg(Arg::with_name("NAME")
+                                .help("New name for the room")
+                                .required(true)
+                                .index(1)))
         .subcommand(SubCommand::with_name("seek").about("Seek to a specific timestamp on the current track")
                         .arg(Arg::with_name("TIMESTAMP")
                                 .help("hh:mm:ss/mm:ss")
@@ -59,248 +532,3109 @@ fn argparse<'a, 'b>() -> clap::App<'a, 'b> {
         .subcommand(SubCommand::with_name("volume").about("Get or set the volume of the speaker")
                         .arg(Arg::with_name("VOLUME")
                                 .help("Percent volume to set speaker to 0-100")
-                                .index(1)))
+                                .index(1))
+                        .arg(format_arg(Volume::FIELDS))
+                        .subcommand(SubCommand::with_name("ramp").about("Gradually change the volume, ie. for a gentle wake up")
+                                .arg(Arg::with_name("FROM")
+                                        .help("Volume to start at, 0-100")
+                                        .required(true)
+                                        .index(1))
+                                .arg(Arg::with_name("TO")
+                                        .help("Volume to end at, 0-100")
+                                        .required(true)
+                                        .index(2))
+                                .arg(Arg::with_name("over")
+                                        .help("How long the ramp should take, as hh:mm:ss, mm:ss or seconds (eg. 30s)")
+                                        .long("over")
+                                        .value_name("DURATION")
+                                        .required(true)
+                                        .takes_value(true))))
+        .subcommand(SubCommand::with_name("fadeout").about("Fade the volume down to nothing and pause, then put the volume back for next time")
+                        .arg(Arg::with_name("over")
+                                .help("How long the fade should take, as hh:mm:ss, mm:ss or seconds (eg. 20s)")
+                                .long("over")
+                                .value_name("DURATION")
+                                .required(true)
+                                .takes_value(true)))
+        .subcommand(SubCommand::with_name("inputs").about("List the speakers with a line-in or TV input"))
+        .subcommand(SubCommand::with_name("raw").about("Send an arbitrary UPnP action to the speaker and print the response (experimental)")
+                        .arg(Arg::with_name("SERVICE")
+                                .help("Service to call, eg. AVTransport or RenderingControl")
+                                .required(true)
+                                .index(1))
+                        .arg(Arg::with_name("ACTION")
+                                .help("Action to call on the service, eg. GetVolume")
+                                .required(true)
+                                .index(2))
+                        .arg(Arg::with_name("ARGS")
+                                .help("Arguments to the action as key=value pairs")
+                                .multiple(true)
+                                .index(3))
+                        .arg(Arg::with_name("experimental")
+                                .help("Acknowledge that raw actions can put the speaker into odd states")
+                                .long("experimental")))
         .subcommand(SubCommand::with_name("rooms").about("List all of your speakers")
-                        .arg(Arg::with_name("invalidate").help("Detect new speakers and room arrangements")))
+                        .arg(Arg::with_name("invalidate")
+                                .help("Ignore the speaker cache and run a fresh SSDP discovery to detect new speakers and room arrangements")
+                                .long("invalidate")
+                                .short("i"))
+                        .arg(Arg::with_name("status")
+                                .help("Show what each group is playing and its volume")
+                                .long("status"))
+                        .arg(Arg::with_name("watch")
+                                .help("Keep running and print a line whenever a room appears, disappears, joins or leaves a group")
+                                .long("watch")
+                                .conflicts_with("status"))
+                        .arg(Arg::with_name("interval")
+                                .help("Seconds between topology checks when watching, 5 unless given")
+                                .long("interval")
+                                .value_name("SECS")
+                                .requires("watch")
+                                .takes_value(true)))
 }
 
-#[tokio::main]
-async fn main() -> Fallible<()> {
-    let args = argparse().get_matches();
+/// Resolve the speaker given by `-c` or `--uuid` for subcommands that operate on one.
+async fn controller(args: &clap::ArgMatches<'_>) -> Fallible<Speaker> {
+    let cache_file = cache_file(args);
 
-    util::setup_logger()?;
+    if let Some(uuid) = args.value_of("uuid") {
+        return util::timed("resolve controller", discovery::find_speaker_by_uuid(&cache_file, uuid)).await;
+    }
 
-    let controller = args.value_of("controller").expect("controller");
-    let speaker = match controller.parse::<IpAddr>() {
-        Ok(ip) => Speaker::from_ip(ip).await?,
-        Err(_) => discovery::find_speaker_by_name(controller).await?,
-    };
+    if let Some(controller) = args.value_of("controller") {
+        return util::timed("resolve controller", discovery::find_speaker(&cache_file, controller)).await;
+    }
 
-    match args.subcommand() {
-        ("track", Some(subargs)) => {
-            match subargs.subcommand() {
-                ("next", _) => speaker.queue().next().await?,
-                ("prev", _) => speaker.queue().previous().await?,
-                ("list", _) => print_struct!(args, &TrackList::new(&speaker).await?),
-                ("play", Some(play_subargs)) => match play_subargs.subcommand_name() {
-                    Some("tv") => speaker.play_tv().await?,
-                    Some("line-in") => speaker.play_line_in().await?,
-                    _ => {
-                        let uri = play_subargs.value_of("uri")
-                            .filter(|s| !s.is_empty())
-                            .ok_or_else(|| format_err!("Must pass [tv], [line-in] or a URI to the play command"))?;
+    let (source, controller) = default_controller().ok_or_else(|| format_err!(
+        "This command requires a controller to be given with -c, --uuid, the {} environment variable or the config file",
+        CONTROLLER_VARS[0],
+    ))?;
 
-                        if let Ok(pos) = uri.parse::<u64>() {
-                            speaker.queue().skip_to(&pos).await?
-                        } else {
-                            speaker.play_track(uri).await?
-                        }
-                    },
-                },
-                _ => print_struct!(args, &Track::new(&speaker).await?)
-            }
-        },
-        ("group", Some(sub)) => {
-            let master = sub.value_of("MASTER").expect("master");
-            speaker.group(&match master.parse::<IpAddr>() {
-                Ok(ip) => Speaker::from_ip(ip).await?,
-                Err(_) => discovery::find_speaker_by_name(master).await?,
-            }).await?
-        },
-        ("ungroup", _) => speaker.ungroup().await?,
-        ("info", _) => print_struct!(args, &Info::new(&speaker)),
-        ("volume", Some(sub)) => match sub.value_of("VOLUME") {
-            Some(volume) => speaker.set_volume(volume.parse()?).await?,
-            None => print_struct!(args, &Volume::new(&speaker).await?),
-        },
-        ("seek", Some(sub)) => {
-            let a = sub.value_of("TIMESTAMP").expect("timestamp");
+    util::timed("resolve controller", discovery::find_speaker(&cache_file, &controller)).await
+        .map_err(|e| {
+            let message = format!("{} (controller '{}' from {})", e, controller, source);
+            e.context(message).into()
+        })
+}
 
-            let mut multiplier = 1;
+/// Environment variables the controller is read from when neither `-c` nor `--uuid` is given,
+/// in order of preference.
+const CONTROLLER_VARS: [&str; 2] = ["SONOS_CONTROLLER", "SONOS_ROOM"];
 
-            let secs = a.split(":").collect::<Vec<&str>>().iter().rfold(0, |curr, iter_val| {
-                let section_value = iter_val.parse::<u64>().expect("can't parse int") * multiplier;
-                multiplier *= 60;
-                curr + section_value
-            });
+/// The controller to use when neither `-c` nor `--uuid` is given along with where it came from,
+/// the environment first and then the config file. Takes the same IPs, room names and UUIDs as
+/// `-c`.
+fn default_controller() -> Option<(&'static str, String)> {
+    CONTROLLER_VARS.iter()
+        .find_map(|var| match std::env::var(var) {
+            Ok(value) if !value.trim().is_empty() => Some((*var, value.trim().to_string())),
+            _ => None,
+        })
+        .or_else(|| config::get().controller.map(|controller| ("the config file", controller)))
+}
 
-            let duration = Duration::new(secs, 0);
+/// Environment variables the speaker cache can be moved with when `--cache-file` isn't given, in
+/// order of preference.
+const CACHE_FILE_VARS: [&str; 2] = ["SONOS_CACHE_FILE", "SONOS_CLI_CACHE"];
 
-            speaker.seek(&duration).await?;
-        },
-        ("rooms", Some(sub)) => {
-            let devices = discovery::discover(true, sub.is_present("invalidate")).await?;
+/// The speaker cache asked for with `--cache-file` or the environment, if there is one.
+fn cache_file_override(args: &clap::ArgMatches<'_>) -> Option<std::path::PathBuf> {
+    args.value_of_os("cache-file")
+        .map(std::path::PathBuf::from)
+        .or_else(|| CACHE_FILE_VARS.iter().find_map(|var| std::env::var_os(var).filter(|path| !path.is_empty()).map(std::path::PathBuf::from)))
+}
 
-            let mut rooms = std::collections::HashMap::new();
+/// The speaker cache to use, the per-user one unless another was asked for.
+fn cache_file(args: &clap::ArgMatches<'_>) -> std::path::PathBuf {
+    cache_file_override(args).unwrap_or_else(discovery::default_cache_file)
+}
 
-            for device in devices {
-                let coordinator = device.coordinator().await?;
+/// Any speaker to run a household-wide command on (ie. alarms), the one given with `-c`,
+/// `--uuid` or the environment if there is one.
+async fn household_speaker(args: &clap::ArgMatches<'_>) -> Fallible<Speaker> {
+    if args.is_present("controller") || args.is_present("uuid") || default_controller().is_some() {
+        return controller(args).await;
+    }
 
-                let mut room = rooms.entry(coordinator).or_insert(Vec::new());
-                room.push(device);
-            }
+    discovery::discover(&cache_file(args), true, false).await?
+        .into_iter()
+        .next()
+        .ok_or_else(|| not_found!("Couldn't find any speakers"))
+}
 
-            for (key, value) in rooms {
-                info!("Controller: {}", key);
+/// Resolve the speaker given by `-c` for transport and queue commands, which only work on a
+/// group's coordinator. A grouped speaker is swapped for its coordinator unless `--no-redirect`
+/// is given.
+async fn coordinator(args: &clap::ArgMatches<'_>) -> Fallible<Speaker> {
+    let speaker = controller(args).await?;
 
-                for device in value {
-                    info!("d:     {}", device.name);
-                }
-            }
-        },
-        _ => {
-            panic!();
-        }
+    if args.is_present("no-redirect") {
+        return Ok(speaker);
     }
 
-    Ok(())
-}
+    let (retries, timeout) = (retries(args)?, timeout(args)?);
+    let group = util::retry(retries, timeout, || topology::group_of(&speaker)).await?;
 
-#[derive(Serialize, Deserialize, Debug)]
-struct TrackListItem {
-    pub position: u64,
-    pub title: String,
-    pub artist: String,
-    pub album: String,
-    pub duration: Duration
-}
-#[derive(Serialize, Deserialize, Debug)]
-struct TrackList(Vec<TrackListItem>);
-impl TrackList {
-    pub async fn new(speaker: &Speaker) -> Fallible<Self> {
-        Ok(Self(
-            speaker.queue().list().await?
-                .into_iter()
-                .map(|v| TrackListItem {
-                    position: v.position,
-                    title: v.title,
-                    artist: v.artist,
-                    album: v.album,
-                    duration: v.duration
-                })
-                .collect()
-        ))
+    match group.coordinator() {
+        Some(coordinator) if coordinator.uuid != speaker.uuid => {
+            util::note(&format!("acting on group coordinator: {}", coordinator.name));
+
+            util::retry(retries, timeout, || Speaker::from_ip(coordinator.ip)).await
+        },
+        _ => Ok(speaker),
     }
 }
-impl std::fmt::Display for TrackList {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        Ok(for item in &self.0 {
-            writeln!(f, "{}: {} - {} ({})",
-                   item.position,
-                   item.artist,
-                   item.title,
-                   util::duration_to_hms(item.duration))?
-        })
+
+/// Run a transport command on the group coordinator. Sonos rejects transport commands sent to a
+/// room that's stopped being its group's coordinator (UPnP error 800), which happens when a script
+/// regroups rooms and then immediately starts playback. With `--retry-on-group-change` a failed
+/// command is run once more if the coordinator has changed since it was resolved.
+async fn on_coordinator<T, F, Fut>(args: &clap::ArgMatches<'_>, f: F) -> Fallible<T>
+where
+    F: Fn(Speaker) -> Fut,
+    Fut: std::future::Future<Output = Fallible<T>>,
+{
+    let speaker = coordinator(args).await?;
+    let uuid = speaker.uuid.clone();
+
+    match f(speaker).await {
+        Err(e) if args.is_present("retry-on-group-change") => {
+            let speaker = coordinator(args).await?;
+
+            if speaker.uuid == uuid {
+                return Err(e);
+            }
+
+            debug!("{}, retrying on the new group coordinator {}", e, speaker.name);
+
+            f(speaker).await
+        },
+        result => result,
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Track {
-    pub title: String,
-    pub artist: String,
-    pub album: Option<String>,
-    pub running_time: Duration,
-    pub duration: Duration
+fn retries(args: &clap::ArgMatches<'_>) -> Fallible<u32> {
+    let retries = args.value_of("retries").unwrap_or("2");
+
+    retries.parse().map_err(|_| invalid_argument!("Expected a number of retries, got {}", retries))
 }
-impl Track {
-    pub async fn new(speaker: &Speaker) -> Fallible<Track> {
-        let track = speaker.track().await?;
 
-        Ok(Self {
-            title: track.title,
-            artist: track.artist,
-            album: track.album,
-            running_time: track.running_time,
-            duration: track.duration
-        })
+fn timeout(args: &clap::ArgMatches<'_>) -> Fallible<Duration> {
+    let timeout = args.value_of("timeout").unwrap_or("5");
+
+    match timeout.parse() {
+        // a zero timeout would fail every operation before it's even sent
+        Ok(0) => Err(invalid_argument!("The timeout has to be at least 1 second")),
+        Ok(secs) => Ok(Duration::from_secs(secs)),
+        Err(_) => Err(invalid_argument!("Expected a timeout in seconds, got {}", timeout)),
     }
 }
-impl std::fmt::Display for Track {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "\u{1F3A4}  {}", self.artist)?;
-        writeln!(f, "\u{1F3B5}  {}", self.title)?;
 
-        if let Some(album) = &self.album {
-            writeln!(f, "\u{1F4BF}  {}", album)?;
+/// Join the speaker to the group the given room is part of.
+async fn join_group(cache_file: &std::path::Path, speaker: &Speaker, room: &str, retries: u32, timeout: Duration) -> Fallible<()> {
+    let target = discovery::find_speaker(cache_file, room).await?;
+
+    // the target might itself be a member of someone else's group
+    let coordinator = util::retry(retries, timeout, || topology::group_of(&target)).await?.coordinator;
+
+    util::retry(retries, timeout, || topology::join(speaker, &coordinator)).await
+}
+
+/// Poll the household's topology every `interval`, printing an event for every room that
+/// appears, disappears, joins or leaves a group. Runs until interrupted, rediscovering the
+/// household if none of the known speakers respond anymore.
+async fn watch_rooms(args: &clap::ArgMatches<'_>, mut devices: Vec<Speaker>, interval: Duration) -> Fallible<()> {
+    use std::collections::BTreeMap;
+
+    // uuid => (room name, coordinator uuid) for every visible member of the household
+    let mut previous: Option<BTreeMap<String, (String, String)>> = None;
+
+    loop {
+        let groups = match topology::household_groups(&devices).await {
+            Ok(groups) => groups,
+            Err(e) => {
+                debug!("Couldn't fetch topology, rediscovering: {}", e);
+                devices = discovery::discover(&cache_file(args), false, true).await.unwrap_or(devices);
+
+                tokio::time::delay_for(interval).await;
+                continue;
+            },
+        };
+
+        let current = groups.iter()
+            .flat_map(|g| g.members.iter().filter(|m| !m.invisible).map(move |m| (m.uuid.clone(), (m.name.clone(), g.coordinator.clone()))))
+            .collect::<BTreeMap<_, _>>();
+
+        if let Some(previous) = &previous {
+            let name_of = |uuid: &str| current.get(uuid).or_else(|| previous.get(uuid))
+                .map(|(name, _)| name.clone())
+                .unwrap_or_else(|| uuid.to_string());
+
+            for (uuid, (name, coordinator)) in &current {
+                let change = match previous.get(uuid) {
+                    None => RoomChange::Appeared { room: name.clone() },
+                    Some((_, old)) if old == coordinator => continue,
+                    Some((_, old)) if coordinator == uuid => RoomChange::Left { room: name.clone(), group: name_of(old) },
+                    Some(_) => RoomChange::Joined { room: name.clone(), group: name_of(coordinator) },
+                };
+
+                print_struct!(args, &RoomEvent::new(change));
+            }
+
+            for (uuid, (name, _)) in previous {
+                if !current.contains_key(uuid) {
+                    print_struct!(args, &RoomEvent::new(RoomChange::Disappeared { room: name.clone() }));
+                }
+            }
         }
 
-        let running_time = util::duration_to_hms(self.running_time);
-        let duration = util::duration_to_hms(self.duration);
+        previous = Some(current);
 
-        write!(f, "\u{23F1}\u{FE0F}  {}/{}", running_time, duration)?;
+        tokio::time::delay_for(interval).await;
+    }
+}
 
-        const PROG_BAR_LEN: usize = 25;
-        let percent_played = ((self.running_time.as_secs() as f64 / self.duration.as_secs() as f64) * PROG_BAR_LEN as f64) as usize;
-        write!(f, " [{}{}]", "\u{2587}".repeat(percent_played), "-".repeat(PROG_BAR_LEN - percent_played))
+/// DIDL-Lite metadata for a URI built from the `--title`, `--artist`, `--album` and `--art`
+/// flags, `None` if none of them were given.
+fn track_metadata(args: &clap::ArgMatches<'_>, uri: &str) -> Option<String> {
+    if !["title", "artist", "album", "art"].iter().any(|flag| args.is_present(flag)) {
+        return None;
     }
+
+    Some(didl::Item {
+        id: "-1",
+        parent_id: "-1",
+        title: args.value_of("title").unwrap_or(uri),
+        class: "object.item.audioItem.musicTrack",
+        creator: args.value_of("artist"),
+        album: args.value_of("album"),
+        album_art: args.value_of("art"),
+        desc: None,
+    }.to_didl())
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Volume {
-    volume: u8,
-    muted: bool,
+/// `--format` for subcommands that print a single result, taking the placeholders it accepts for
+/// the help text.
+fn format_arg(fields: &[&str]) -> clap::Arg<'static, 'static> {
+    clap::Arg::with_name("format")
+        .long("format")
+        .value_name("TEMPLATE")
+        .takes_value(true)
+        .help(Box::leak(format!(
+            "Print the result using a template instead, ie. '{{artist}} - {{title}}'. Use {{{{ and }}}} for literal braces. Placeholders: {}",
+            fields.join(", "),
+        ).into_boxed_str()))
 }
-impl Volume {
-    pub async fn new(speaker: &Speaker) -> Result<Volume, failure::Error> {
-        Ok(Self {
-            volume: speaker.volume().await?,
-            muted: speaker.muted().await?,
-        })
+
+/// The `--format` template given to a subcommand, checked against the placeholders its result
+/// can fill in before anything is fetched.
+fn format_template(args: &clap::ArgMatches<'_>, fields: &[&str]) -> Fallible<Option<util::Template>> {
+    match args.value_of("format") {
+        Some(_) if util::json() => Err(invalid_argument!("--format can't be combined with --json")),
+        Some(template) => util::Template::parse(template, fields).map(Some),
+        None => Ok(None),
     }
 }
-impl std::fmt::Display for Volume {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        const MAX_VOLUME: usize = 100;
-        const PROG_BAR_LEN: usize = 25;
 
-        let pictogram = if self.muted {
-            "\u{1F507}"
-        } else {
-            "\u{1F50A}"
-        };
+/// Parse the `--over` duration of a volume ramp or fade, which also accepts seconds with an `s`
+/// suffix.
+fn ramp_duration(args: &clap::ArgMatches<'_>) -> Fallible<Duration> {
+    let over = util::hms_to_duration(args.value_of("over").expect("over").trim_end_matches('s'))?;
 
-        write!(f, "{} {}/{}", pictogram, self.volume, MAX_VOLUME)?;
+    if over.as_secs() == 0 {
+        bail!("The volume change needs to take at least a second");
+    }
 
-        let percent = (self.volume as usize * PROG_BAR_LEN) / MAX_VOLUME;
+    Ok(over)
+}
+
+/// Parse `Left=<speaker> Right=<speaker>` into the left and right speaker identifiers.
+fn parse_pair<'a>(values: impl Iterator<Item = &'a str>) -> Fallible<(&'a str, &'a str)> {
+    let (mut left, mut right) = (None, None);
 
-        write!(f, " [{}{}]", "\u{2587}".repeat(percent), "-".repeat(PROG_BAR_LEN - percent))
+    for value in values {
+        let mut parts = value.splitn(2, '=');
+
+        match (parts.next().map(str::to_lowercase).as_deref(), parts.next()) {
+            (Some("left"), Some(speaker)) => left = Some(speaker),
+            (Some("right"), Some(speaker)) => right = Some(speaker),
+            _ => return Err(invalid_argument!("Expected Left=<IP or Room Name> or Right=<IP or Room Name>, got {}", value)),
+        }
     }
-}
 
-#[derive(Serialize, Deserialize, Debug)]
-struct Info {
-    pub ip: IpAddr,
-    pub model: String,
-    pub model_number: String,
-    pub software_version: String,
-    pub hardware_version: String,
-    pub serial_number: String,
-    pub name: String,
-    pub uuid: String,
+    Ok((
+        left.ok_or_else(|| format_err!("Missing the Left=<IP or Room Name> speaker"))?,
+        right.ok_or_else(|| format_err!("Missing the Right=<IP or Room Name> speaker"))?,
+    ))
 }
-impl Info {
-    pub fn new(speaker: &Speaker) -> Info {
-        Info {
-            ip: speaker.ip.clone(),
-            model: speaker.model.clone(),
-            model_number: speaker.model_number.clone(),
-            software_version: speaker.software_version.clone(),
-            hardware_version: speaker.hardware_version.clone(),
-            serial_number: speaker.serial_number.clone(),
-            name: speaker.name.clone(),
-            uuid: speaker.uuid.clone(),
+
+#[tokio::main]
+async fn main() {
+    let result = util::timed("total", run(argparse().get_matches())).await;
+
+    let result = match result {
+        Ok(()) if upnp::dry_run() => print_planned(),
+        result => result,
+    };
+
+    if let Err(e) = result {
+        let kind = error_kind(&e);
+
+        // JSON consumers only read stdout, so they get the error there in a shape they can parse
+        if util::json() {
+            let _ = util::output(&util::to_json(&util::Envelope::error(kind, e.to_string())).unwrap_or_default());
+        } else {
+            eprintln!("{} {}", util::paint_stderr(util::Style::Red, "Error:"), e);
         }
+
+        for cause in e.iter_causes() {
+            debug!("caused by: {}", cause);
+        }
+
+        std::process::exit(if kind == "ambiguous" { 2 } else { 1 });
     }
 }
-impl std::fmt::Display for Info {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        writeln!(f, "\u{1F508}  {}", self.name)?;
-        writeln!(f, "{}", "=".repeat(self.name.len() + 3))?;
 
-        writeln!(f, "Model: {} ({})", self.model, self.model_number)?;
+/// Print what `--dry-run` held back. Under `--json` it's added to the command's own result as
+/// `planned`, so there's still only one document.
+fn print_planned() -> Fallible<()> {
+    let planned = upnp::planned();
 
-        writeln!(f, "Versions: Software {}, Hardware {}", self.software_version, self.hardware_version)?;
-        writeln!(f, "Serial number: {}", self.serial_number)?;
-        writeln!(f, "UUID: {}", self.uuid)
+    if util::json() {
+        let envelope = match util::take_held_json() {
+            Some(mut result) => {
+                result["planned"] = serde_json::to_value(&planned)?;
+                result
+            },
+            None => serde_json::to_value(util::Envelope::planned(planned))?,
+        };
+
+        return util::output(&util::to_json(&envelope)?);
+    }
+
+    if planned.is_empty() {
+        return util::output("Dry run, nothing would have been changed");
+    }
+
+    util::output(&format!(
+        "Dry run, would have sent:\n{}",
+        planned.iter().map(|action| format!("  {}", action)).collect::<Vec<_>>().join("\n"),
+    ))
+}
+
+/// Sort a failure into one of a handful of kinds scripts can act on: `ambiguous`, `not_found`,
+/// `invalid_argument`, `network`, `upnp_fault` or the catch-all `error`.
+fn error_kind(e: &failure::Error) -> &'static str {
+    // errors given more context along the way still count as whatever they started out as
+    let is = |is_kind: fn(&dyn failure::Fail) -> bool| e.iter_chain().any(is_kind);
+
+    if is(|e| e.downcast_ref::<discovery::AmbiguousName>().is_some()) {
+        "ambiguous"
+    } else if is(|e| e.downcast_ref::<upnp::Fault>().is_some()) {
+        "upnp_fault"
+    } else if is(|e| e.downcast_ref::<upnp::HttpError>().is_some()
+        || e.downcast_ref::<reqwest::Error>().is_some()
+        || e.downcast_ref::<std::io::Error>().is_some()
+        || e.downcast_ref::<util::TimedOut>().is_some())
+    {
+        "network"
+    } else if is(|e| e.downcast_ref::<util::NotFound>().is_some()) {
+        "not_found"
+    } else if is(|e| e.downcast_ref::<util::InvalidArgument>().is_some()) {
+        "invalid_argument"
+    } else {
+        "error"
+    }
+}
+
+async fn run(args: clap::ArgMatches<'static>) -> Fallible<()> {
+    util::setup_logger(args.is_present("verbose"))?;
+
+    let mut command = Vec::new();
+    let mut matches = args.subcommand();
+
+    while let (name, Some(sub)) = matches {
+        command.push(name);
+        matches = sub.subcommand();
+    }
+
+    util::set_command(command.join(" "));
+
+    // the path is still useful when the file it points at is broken
+    if args.subcommand_matches("config").is_some_and(|sub| sub.subcommand_name() == Some("path")) {
+        util::output(&config::path().display().to_string())?;
+        return Ok(());
+    }
+
+    let config = config::load()?;
+
+    let delimited = args.value_of("output-format").map(str::parse::<util::Delimited>).transpose()?;
+    util::set_delimited(delimited);
+    util::set_no_header(args.is_present("no-header"));
+
+    // a config file asking for JSON gives way to an explicit --output-format or --no-json
+    util::set_json((args.is_present("json") || (config.json && !args.is_present("no-json"))) && delimited.is_none());
+
+    // compact JSON is the default, and there's nothing to make pretty without it
+    if args.is_present("pretty") && !util::json() {
+        return Err(invalid_argument!("--pretty only applies to JSON output, pass --json as well"));
+    }
+
+    util::set_pretty(args.is_present("pretty"));
+    // nothing machine readable should ever have escape codes in it
+    util::set_color(match args.value_of("color") {
+        _ if util::json() || delimited.is_some() => util::ColorChoice::Never,
+        Some(choice) => choice.parse()?,
+        None => util::ColorChoice::Auto,
+    });
+    util::set_ascii(args.is_present("ascii") || config.no_emoji || util::locale_is_ascii());
+    util::set_assume_yes(args.is_present("yes"));
+    util::set_no_input(args.is_present("no-input"));
+    util::set_timing(args.is_present("timing"));
+    util::set_quiet(args.is_present("quiet"));
+    upnp::set_dry_run(args.is_present("dry-run"));
+    // status bars show whatever's written, so the spinner can't ever be allowed near one
+    let oneline = args.subcommand_matches("status").is_some_and(|sub| sub.is_present("oneline") || sub.is_present("waybar"));
+    discovery::set_progress(!util::json() && delimited.is_none() && !args.is_present("quiet") && !oneline);
+    // hh:mm:ss isn't something spreadsheets or awk can add up
+    util::set_raw_seconds(args.is_present("raw-seconds") || delimited.is_some());
+
+    if let Some(output) = args.value_of("output") {
+        util::set_output(output, args.is_present("append"))?;
+    }
+
+    for range in args.values_of("scan").into_iter().flatten() {
+        discovery::add_scan_range(range)?;
+    }
+
+    if let Some(interface) = args.value_of("interface") {
+        discovery::set_interface(interface)?;
+    }
+
+    if let Some(bind) = args.value_of("bind") {
+        discovery::set_bind_address(bind.parse().map_err(|_| invalid_argument!("Expected an IPv4 address to bind to, got {}", bind))?);
+    }
+
+    let cache_ttl = args.value_of("cache-ttl").unwrap_or("24");
+    discovery::set_strict_cache(args.is_present("strict-cache"));
+    discovery::set_cache_ttl(cache_ttl.parse::<u64>().ok()
+        .and_then(|hours| hours.checked_mul(60 * 60))
+        .map(Duration::from_secs)
+        .ok_or_else(|| invalid_argument!("Expected a cache TTL in hours, got {}", cache_ttl))?);
+
+    let retries = retries(&args)?;
+    let timeout = timeout(&args)?;
+
+    if let Some(cache_file) = cache_file_override(&args) {
+        discovery::check_cache_file(&cache_file)?;
+    }
+
+    let cache_file = cache_file(&args);
+
+    match config.discovery_timeout {
+        Some(discovery_timeout) if args.occurrences_of("discovery-timeout") == 0 => discovery::set_timeout(discovery_timeout)?,
+        _ => {
+            let discovery_timeout = args.value_of("discovery-timeout").unwrap_or("2");
+            discovery::set_timeout(discovery_timeout.parse()
+                .map_err(|_| invalid_argument!("Expected a discovery timeout in seconds, got {}", discovery_timeout))?)?;
+        },
+    }
+
+    let discovery_rounds = args.value_of("discovery-rounds").unwrap_or("3");
+    discovery::set_rounds(discovery_rounds.parse()
+        .map_err(|_| invalid_argument!("Expected a number of discovery rounds, got {}", discovery_rounds))?)?;
+
+    if args.is_present("all") || args.occurrences_of("controller") > 1 {
+        let results = MultiResult::new(&args, retries, timeout).await?;
+        print_struct!(args, &results);
+
+        if results.0.iter().any(|r| !r.ok) {
+            std::process::exit(1);
+        }
+
+        return Ok(());
+    }
+
+    match args.subcommand() {
+        ("track", Some(subargs)) => {
+            let template = format_template(subargs, Track::FIELDS)?;
+
+            // switching inputs takes the speaker itself out of its group, everything else is
+            // handled by the coordinator
+            let speaker = match subargs.subcommand_matches("play").and_then(|m| m.subcommand_name()) {
+                Some("tv") | Some("line-in") => controller(&args).await?,
+                _ => coordinator(&args).await?,
+            };
+
+            let wait = match subargs.subcommand() {
+                ("next", Some(m)) | ("prev", Some(m)) | ("play", Some(m)) => m.is_present("wait"),
+                _ => false,
+            };
+
+            // skipping keeps paused speakers paused, so only wait for playback if it was playing
+            let was_playing = match subargs.subcommand_name() {
+                Some("next") | Some("prev") if wait => util::timeout(timeout, transport::state(speaker.ip)).await? == "PLAYING",
+                _ => true,
+            };
+
+            match subargs.subcommand() {
+                ("next", _) => util::timeout(timeout, transport::next(speaker.ip)).await?,
+                ("prev", _) => util::timeout(timeout, transport::previous(speaker.ip)).await?,
+                ("current", _) => print_struct!(args, &util::retry(retries, timeout, || QueuePosition::new(&speaker)).await?),
+                ("list", Some(list_args)) => {
+                    let table = match list_args.is_present("table") {
+                        true => Some(util::columns(TrackList::COLUMNS, TrackList::DEFAULT_COLUMNS, list_args.value_of("columns"))?),
+                        false => None,
+                    };
+
+                    let mut list = util::retry(retries, timeout, || TrackList::new(&speaker, TrackFilter::from_args(list_args))).await?;
+                    list.show_uris = list_args.is_present("uris");
+                    list.table = table;
+
+                    if list.tracks.is_empty() && !list.filters.is_empty() {
+                        return Err(not_found!("No matches for the given filters"));
+                    }
+
+                    print_struct!(args, &list)
+                },
+                ("play", Some(play_subargs)) => match play_subargs.subcommand_name() {
+                    Some("tv") => {
+                        let uri = format!("x-sonos-htastream:{}:spdif", speaker.uuid);
+
+                        util::retry(retries, timeout, || transport::set_uri(speaker.ip, &uri, "")).await?;
+                        util::retry(retries, timeout, || transport::play(speaker.ip)).await?
+                    },
+                    Some("line-in") => match play_subargs.subcommand_matches("line-in").and_then(|m| m.value_of("from")) {
+                        Some(room) => {
+                            let source = discovery::find_speaker(&cache_file, room).await?;
+
+                            util::timeout(timeout, transport::set_uri(speaker.ip, &format!("x-rincon-stream:{}", source.uuid), "")).await?;
+                            util::timeout(timeout, transport::play(speaker.ip)).await?
+                        },
+                        None => {
+                            let uri = format!("x-rincon-stream:{}", speaker.uuid);
+
+                            util::retry(retries, timeout, || transport::set_uri(speaker.ip, &uri, "")).await?;
+                            util::retry(retries, timeout, || transport::play(speaker.ip)).await?
+                        },
+                    },
+                    _ => {
+                        let uri = play_subargs.value_of("uri")
+                            .filter(|s| !s.is_empty())
+                            .ok_or_else(|| format_err!("Must pass [tv], [line-in] or a URI to the play command"))?;
+
+                        let metadata = track_metadata(play_subargs, uri);
+
+                        if play_subargs.is_present("append") {
+                            let enqueued = util::timeout(timeout, queue::add(&speaker, uri, metadata.as_deref().unwrap_or(""), 0)).await?;
+
+                            if util::timeout(timeout, speaker.transport_state()).await? != TransportState::Playing {
+                                util::timeout(timeout, queue::use_queue(&speaker)).await?;
+                                util::timeout(timeout, transport::skip_to(speaker.ip, enqueued.position)).await?;
+                                util::timeout(timeout, transport::play(speaker.ip)).await?;
+                            }
+
+                            print_struct!(args, &enqueued)
+                        } else if let Ok(pos) = uri.parse::<u64>() {
+                            util::retry(retries, timeout, || transport::skip_to(speaker.ip, pos)).await?
+                        } else {
+                            util::timeout(timeout, transport::set_uri(speaker.ip, uri, metadata.as_deref().unwrap_or(""))).await?;
+                            util::timeout(timeout, transport::play(speaker.ip)).await?
+                        }
+                    },
+                },
+                _ => {
+                    let track = util::retry(retries, timeout, || Track::new(&speaker)).await?;
+
+                    match template {
+                        Some(template) => {
+                            let state = match template.uses("state") {
+                                true => util::retry(retries, timeout, || transport::state(speaker.ip)).await?,
+                                false => String::new(),
+                            };
+
+                            util::output(&template.render(&track, &[
+                                ("elapsed", util::duration_to_hms(track.running_time)),
+                                ("percent", track.percent_played().to_string()),
+                                ("state", state),
+                            ])?)?
+                        },
+                        None => print_struct!(args, &track),
+                    }
+                },
+            }
+
+            if wait && was_playing {
+                transport::wait_for_state(speaker.ip, timeout, "PLAYING", |state| state == "PLAYING").await?;
+            } else if wait {
+                transport::wait_for_state(speaker.ip, timeout, "a settled state", |state| state != "TRANSITIONING").await?;
+            }
+        },
+        ("play-radio", Some(sub)) => {
+            let station = sub.value_of("STATION").expect("station");
+
+            if !station.starts_with('s') || station[1..].parse::<u64>().is_err() {
+                return Err(invalid_argument!("Expected a TuneIn station ID such as s24940, got {}", station));
+            }
+
+            let metadata = didl::Item {
+                id: &format!("F00092020{}", station),
+                parent_id: "L",
+                title: sub.value_of("title").unwrap_or(station),
+                class: "object.item.audioItem.audioBroadcast",
+                desc: Some("SA_RINCON65031_"),
+                ..didl::Item::default()
+            }.to_didl();
+
+            let uri = format!("x-sonosapi-stream:{}?sid=254&flags=8224&sn=0", station);
+
+            let (uri, metadata) = (&uri, &metadata);
+
+            on_coordinator(&args, move |speaker| async move {
+                util::retry(retries, timeout, || transport::set_uri(speaker.ip, uri, metadata)).await?;
+                util::retry(retries, timeout, || transport::play(speaker.ip)).await
+            }).await?
+        },
+        ("play-url", Some(sub)) => {
+            let probed = util::timeout(timeout, probe::probe(sub.value_of("URL").expect("url"), sub.value_of("title"))).await?;
+            let probed = &probed;
+
+            on_coordinator(&args, move |speaker| async move {
+                util::retry(retries, timeout, || transport::set_uri(speaker.ip, &probed.uri, &probed.metadata)).await?;
+                util::retry(retries, timeout, || transport::play(speaker.ip)).await
+            }).await?;
+
+            print_struct!(args, probed)
+        },
+        ("play-file", Some(sub)) => {
+            // the speaker has to fetch the file from us while it plays, there's no holding that back
+            if upnp::dry_run() {
+                return Err(invalid_argument!("play-file can't be run with --dry-run"));
+            }
+
+            let speaker = coordinator(&args).await?;
+
+            let finished = serve::play_file(&speaker, std::path::Path::new(sub.value_of("FILE").expect("file")), |uri| {
+                util::note(&format!("serving {} to {}, press Ctrl-C to stop", uri, speaker.name));
+            }).await?;
+
+            if !finished {
+                std::process::exit(130);
+            }
+        },
+        ("favorite", Some(sub)) => match sub.value_of("NAME") {
+            Some(name) => {
+                let speaker = controller(&args).await?;
+                let favorite = favorites::find(&util::retry(retries, timeout, || favorites::list(&speaker)).await?, name).await?;
+                let favorite = &favorite;
+
+                on_coordinator(&args, move |speaker| async move {
+                    util::timeout(timeout, favorites::play(&speaker, favorite)).await
+                }).await?;
+
+                print_struct!(args, &FavoriteList(vec![favorite.clone()]))
+            },
+            None => {
+                let speaker = controller(&args).await?;
+
+                print_struct!(args, &FavoriteList(util::retry(retries, timeout, || favorites::list(&speaker)).await?))
+            },
+        },
+        ("queue", Some(subargs)) => {
+            let speaker = coordinator(&args).await?;
+
+            match subargs.subcommand() {
+                ("add", Some(add_args)) => {
+                    let uri = add_args.value_of("uri").expect("uri");
+                    let at = add_args.value_of("at").map(str::parse).transpose()?.unwrap_or(0);
+
+                    print_struct!(args, &util::timeout(timeout, queue::insert(&speaker, uri, at)).await?)
+                },
+                ("play-next", Some(next_args)) => {
+                    let uri = next_args.value_of("uri").expect("uri");
+                    let enqueued = util::timeout(timeout, queue::play_next(&speaker, uri, "")).await?;
+
+                    let mut list = util::retry(retries, timeout, || TrackList::new(&speaker, TrackFilter::default())).await?;
+                    list.inserted = Some(enqueued);
+
+                    print_struct!(args, &list)
+                },
+                _ => unreachable!("clap requires a queue subcommand"),
+            }
+        },
+        ("group", Some(subargs)) => {
+            let speaker = controller(&args).await?;
+
+            match subargs.subcommand() {
+                ("join", Some(join_args)) => join_group(&cache_file, &speaker, join_args.value_of("ROOM").expect("room"), retries, timeout).await?,
+                // `group <MASTER>` predates the subcommands and is kept as shorthand for `group join`
+                ("", None) => join_group(&cache_file, &speaker, subargs.value_of("MASTER").expect("master"), retries, timeout).await?,
+                ("leave", _) => util::retry(retries, timeout, || topology::leave(&speaker)).await?,
+                ("all", Some(all_args)) => {
+                    let except = all_args.values_of("except").map(Iterator::collect).unwrap_or_default();
+
+                    print_struct!(args, &PartyResult::new(&cache_file, &speaker, except).await?);
+                    return Ok(());
+                },
+                _ => unreachable!("clap requires a master or a group subcommand"),
+            }
+
+            print_struct!(args, &Group::try_from(&util::retry(retries, timeout, || topology::group_of(&speaker)).await?)?)
+        },
+        ("ungroup", _) => {
+            let speaker = controller(&args).await?;
+
+            util::retry(retries, timeout, || topology::leave(&speaker)).await?
+        },
+        ("satellites", Some(subargs)) => {
+            const HT_CONTROL: &str = "urn:schemas-upnp-org:service:HTControl:1";
+
+            let speaker = controller(&args).await?;
+
+            if !util::timeout(timeout, upnp::services(speaker.ip)).await?.iter().any(|s| s == HT_CONTROL) {
+                bail!("{} ({}) isn't a soundbar", speaker.name, speaker.model);
+            }
+
+            let group = util::timeout(timeout, topology::group_of(&speaker)).await?;
+            let soundbar = group.members.iter()
+                .find(|m| m.uuid == speaker.uuid)
+                .ok_or_else(|| format_err!("{} isn't part of its own group", speaker.name))?;
+
+            match subargs.subcommand() {
+                ("add", Some(add_args)) => {
+                    let (left, right) = futures::try_join!(
+                        discovery::find_speaker(&cache_file, add_args.value_of("LEFT").expect("left")),
+                        discovery::find_speaker(&cache_file, add_args.value_of("RIGHT").expect("right")),
+                    )?;
+
+                    if left.uuid == right.uuid {
+                        return Err(invalid_argument!("The left and right surrounds must be different speakers"));
+                    }
+
+                    if left.model != right.model {
+                        return Err(invalid_argument!("Surrounds must be the same model, got a {} and a {}", left.model, right.model));
+                    }
+
+                    if util::timeout(timeout, upnp::services(left.ip)).await?.iter().any(|s| s == HT_CONTROL) {
+                        bail!("A {} can't be used as a surround", left.model);
+                    }
+
+                    util::timeout(timeout, topology::add_surrounds(soundbar, &left, &right)).await?;
+
+                    warn!("{} and {} are now part of {} and no longer show up as rooms of their own", left.name, right.name, speaker.name);
+                },
+                ("remove", _) => {
+                    let surrounds = group.bonded(soundbar)
+                        .into_iter()
+                        .filter(|b| soundbar.role_of(&b.uuid).is_some_and(|role| role.ends_with("surround")))
+                        .collect::<Vec<_>>();
+
+                    if surrounds.is_empty() {
+                        bail!("{} doesn't have any surrounds bonded to it", speaker.name);
+                    }
+
+                    for surround in surrounds {
+                        util::timeout(timeout, topology::remove_satellite(speaker.ip, &surround.uuid)).await?;
+                    }
+                },
+                _ => unreachable!("clap requires a satellites subcommand"),
+            }
+
+            let group = util::timeout(timeout, topology::group_of(&speaker)).await?;
+            let soundbar = group.members.iter()
+                .find(|m| m.uuid == speaker.uuid)
+                .ok_or_else(|| format_err!("{} isn't part of its own group", speaker.name))?;
+
+            print_struct!(args, &RoomInfo::new(&group, soundbar))
+        },
+        ("pair", Some(subargs)) => match subargs.subcommand() {
+            ("create", Some(create_args)) => {
+                let (left, right) = parse_pair(create_args.values_of("SPEAKERS").expect("speakers"))?;
+                let (left, right) = futures::try_join!(discovery::find_speaker(&cache_file, left), discovery::find_speaker(&cache_file, right))?;
+
+                if left.uuid == right.uuid {
+                    bail!("Can't pair {} with itself", left.name);
+                }
+
+                if left.model != right.model {
+                    return Err(invalid_argument!("Can't pair a {} with a {}, both speakers must be the same model", left.model, right.model));
+                }
+
+                util::timeout(timeout, topology::create_stereo_pair(&left, &right)).await?;
+
+                print_struct!(args, &StereoPair {
+                    name: left.name.clone(),
+                    left: RoomInfo::from(&left),
+                    right: RoomInfo::from(&right),
+                    separated: false,
+                })
+            },
+            ("separate", Some(separate_args)) => {
+                let speaker = discovery::find_speaker(&cache_file, separate_args.value_of("ROOM").expect("room")).await?;
+                let group = util::timeout(timeout, topology::group_of(&speaker)).await?;
+
+                let (channel_map, left, right) = topology::stereo_pair(&group, &speaker.uuid)
+                    .ok_or_else(|| format_err!("{} isn't a stereo pair", speaker.name))?;
+
+                util::timeout(timeout, topology::separate_stereo_pair(left.ip, channel_map)).await?;
+
+                print_struct!(args, &StereoPair {
+                    name: left.name.clone(),
+                    left: RoomInfo::new(&group, left),
+                    right: RoomInfo::new(&group, right),
+                    separated: true,
+                })
+            },
+            _ => unreachable!("clap requires a pair subcommand"),
+        },
+        ("ungroup-all", Some(sub)) => print_struct!(args, &UngroupResult::new(&cache_file, sub.value_of("room")).await?),
+        ("transfer", Some(sub)) => {
+            let (from, to) = futures::try_join!(
+                discovery::find_speaker(&cache_file, sub.value_of("FROM").expect("from")),
+                discovery::find_speaker(&cache_file, sub.value_of("TO").expect("to")),
+            )?;
+
+            print_struct!(args, &Transfer::new(&from, &to, sub.is_present("force")).await?)
+        },
+        ("alarms", Some(subargs)) => {
+            let speaker = household_speaker(&args).await?;
+
+            match subargs.subcommand() {
+                ("list", _) => {
+                    let only_room = args.is_present("controller") || args.is_present("uuid");
+
+                    let alarms = util::retry(retries, timeout, || alarms::list(speaker.ip)).await?
+                        .into_iter()
+                        .filter(|alarm| !only_room || alarm.room_uuid == speaker.uuid)
+                        .collect();
+
+                    print_struct!(args, &AlarmList::new(&cache_file, alarms).await?)
+                },
+                ("create", Some(sub)) => {
+                    let room = match sub.value_of("room") {
+                        Some(room) => discovery::find_speaker(&cache_file, room).await?,
+                        None if args.is_present("controller") || args.is_present("uuid") || default_controller().is_some() => speaker,
+                        None => bail!("Pass the room the alarm should play in with --room or -c"),
+                    };
+
+                    let uri = sub.value_of("uri").unwrap_or("x-rincon-buzzer:0");
+
+                    // the chime doesn't need any metadata but everything else needs a title to show
+                    let metadata = if sub.is_present("uri") {
+                        didl::Item {
+                            id: "-1",
+                            parent_id: "-1",
+                            title: sub.value_of("title").unwrap_or(uri),
+                            class: "object.item.audioItem.musicTrack",
+                            ..didl::Item::default()
+                        }.to_didl()
+                    } else {
+                        String::new()
+                    };
+
+                    let alarm = alarms::Alarm {
+                        id: 0,
+                        start_time: alarms::parse_time(sub.value_of("time").expect("time"))?,
+                        duration: util::hms_to_duration(sub.value_of("duration").expect("duration"))?,
+                        recurrence: alarms::parse_recurrence(sub.value_of("recurrence").expect("recurrence"))?,
+                        room_uuid: room.uuid.clone(),
+                        room: Some(room.name.clone()),
+                        enabled: !sub.is_present("disabled"),
+                        volume: config::cap_volume(&room.name, volume::parse(sub.value_of("volume").expect("volume"))?),
+                        program_uri: uri.to_string(),
+                        program_metadata: metadata,
+                        play_mode: sub.value_of("play-mode").expect("play mode").to_string(),
+                        include_linked_zones: sub.is_present("include-grouped"),
+                    };
+
+                    let id = util::timeout(timeout, alarms::create(room.ip, &alarm)).await?;
+
+                    print_struct!(args, &AlarmCreated { id })
+                },
+                ("delete", Some(sub)) => {
+                    let id = sub.value_of("ID").expect("id");
+                    let id = id.parse().map_err(|_| invalid_argument!("Expected a numeric alarm ID, got {}", id))?;
+
+                    let alarm = util::retry(retries, timeout, || alarms::get(speaker.ip, id)).await?;
+                    let room = discovery::cached_speakers(&cache_file, true, false).await?
+                        .into_iter()
+                        .find(|s| s.uuid == alarm.room_uuid)
+                        .map_or(alarm.room_uuid, |s| s.name);
+
+                    if !util::confirm(&format!("Delete alarm {} ({} {} in {})?", id, alarm.start_time, alarm.recurrence, room)).await? {
+                        bail!("Not deleting alarm {}", id);
+                    }
+
+                    util::timeout(timeout, alarms::delete(speaker.ip, id)).await?;
+
+                    print_struct!(args, &AlarmDeleted {
+                        deleted: id,
+                        remaining: AlarmList::new(&cache_file, util::retry(retries, timeout, || alarms::list(speaker.ip)).await?).await?,
+                    })
+                },
+                (command @ "enable", Some(sub)) | (command @ "disable", Some(sub)) => {
+                    let id = sub.value_of("ID").expect("id");
+                    let id = id.parse().map_err(|_| invalid_argument!("Expected a numeric alarm ID, got {}", id))?;
+
+                    let alarm = util::timeout(timeout, alarms::set_enabled(speaker.ip, id, command == "enable")).await?;
+
+                    print_struct!(args, &AlarmList::new(&cache_file, vec![alarm]).await?)
+                },
+                _ => unreachable!("clap requires an alarms subcommand"),
+            }
+        },
+        ("use", Some(sub)) => {
+            if sub.is_present("clear") {
+                config::set_controller(None)?;
+            } else if let Some(room) = sub.value_of("ROOM") {
+                let speaker = discovery::find_speaker(&cache_file, room).await?;
+
+                // the UUID keeps working when the room's renamed or its IP changes
+                config::set_controller(Some((&speaker.uuid, &speaker.name)))?;
+            }
+
+            print_struct!(args, &DefaultController::new(&cache_file).await)
+        },
+        ("speaker", Some(subargs)) => match subargs.subcommand() {
+            ("add", Some(add_args)) => {
+                let ip = add_args.value_of("IP").expect("ip");
+                let ip = discovery::parse_ip(ip).ok_or_else(|| invalid_argument!("Expected an IP address, got {}", ip))?;
+
+                let speaker = util::retry(retries, timeout, || Speaker::from_ip(ip)).await
+                    .map_err(|e| format_err!("{} doesn't look like a Sonos speaker: {}", ip, e))?;
+
+                discovery::add_manual(&cache_file, &speaker).await?;
+
+                print_struct!(args, &ManualSpeakers(vec![discovery::CachedSpeaker::from(&speaker)]))
+            },
+            ("remove", Some(remove_args)) => {
+                let removed = discovery::remove_manual(&cache_file, remove_args.value_of("SPEAKER").expect("speaker")).await?;
+
+                print_struct!(args, &ManualSpeakers(vec![removed]))
+            },
+            ("list", _) => print_struct!(args, &ManualSpeakers(discovery::manual_speakers(&cache_file).await)),
+            _ => unreachable!("clap requires a speaker subcommand"),
+        },
+        ("snapshot", Some(subargs)) => match subargs.subcommand() {
+            ("save", Some(save_args)) => {
+                let devices = discovery::discover(&cache_file, true, false).await?;
+                let snapshot = snapshot::capture(save_args.value_of("NAME").expect("name"), &devices).await?;
+
+                snapshot::save(&snapshot).await?;
+
+                print_struct!(args, &SnapshotSummary::from(&snapshot))
+            },
+            ("restore", Some(restore_args)) => {
+                let snapshot = snapshot::load(restore_args.value_of("NAME").expect("name")).await?;
+                let restored = snapshot::restore(&cache_file, &snapshot).await?;
+
+                print_struct!(args, &SnapshotRestore {
+                    name: snapshot.name,
+                    groups: restored.groups,
+                    missing: restored.missing,
+                })
+            },
+            ("list", _) => print_struct!(args, &SnapshotList(snapshot::list().await?.iter().map(SnapshotSummary::from).collect())),
+            ("delete", Some(delete_args)) => snapshot::delete(delete_args.value_of("NAME").expect("name")).await?,
+            _ => unreachable!("clap requires a snapshot subcommand"),
+        },
+        ("play", Some(sub)) => {
+            let wait = sub.is_present("wait");
+
+            on_coordinator(&args, move |speaker| async move {
+                util::retry(retries, timeout, || transport::play(speaker.ip)).await?;
+
+                if wait {
+                    transport::wait_for_state(speaker.ip, timeout, "PLAYING", |state| state == "PLAYING").await?;
+                }
+
+                Ok(())
+            }).await?
+        },
+        ("pause", _) => on_coordinator(&args, move |speaker| async move {
+            util::retry(retries, timeout, || transport::pause(speaker.ip)).await
+        }).await?,
+        ("stop", _) => on_coordinator(&args, move |speaker| async move {
+            util::retry(retries, timeout, || transport::stop(speaker.ip)).await
+        }).await?,
+        ("rename", Some(sub)) => {
+            let speaker = controller(&args).await?;
+            let name = sub.value_of("NAME").expect("name").trim();
+
+            if name.is_empty() {
+                bail!("Room names can't be empty");
+            }
+
+            let group = util::timeout(timeout, topology::group_of(&speaker)).await?;
+
+            if topology::stereo_pair(&group, &speaker.uuid).is_some() {
+                warn!("{} is half of a stereo pair, both speakers share a single room name", speaker.name);
+            }
+
+            let old = util::timeout(timeout, topology::rename(speaker.ip, name)).await?;
+
+            // the speaker keeps its name under --dry-run, so the cache has to as well
+            if !upnp::dry_run() {
+                discovery::rename_cached(&cache_file, &speaker.uuid, name).await;
+            }
+
+            print_struct!(args, &Renamed { old, new: name.to_string() })
+        },
+        ("info", Some(sub)) => {
+            let template = format_template(sub, Info::FIELDS)?;
+            let speaker = controller(&args).await?;
+            let mut info = Info::new(&speaker);
+
+            if sub.is_present("network") {
+                info.network = Some(NetworkInfo::new(&speaker).await);
+            }
+
+            match template {
+                Some(template) => util::output(&template.render(&info, &[])?)?,
+                None => print_struct!(args, &info),
+            }
+        },
+        ("find", Some(sub)) => {
+            let name = sub.value_of("NAME").expect("name");
+            let info = Info::new(&discovery::find_speaker(&cache_file, name).await?);
+
+            // the IP on its own is what scripts are after, there's nothing to strip off it
+            if util::json() {
+                print_struct!(args, &info)
+            } else {
+                util::output(&info.ip.to_string())?
+            }
+        },
+        ("config", Some(sub)) => match sub.subcommand_name() {
+            Some("show") => {
+                let mut effective = config.clone();
+
+                effective.controller = args.value_of("uuid")
+                    .or_else(|| args.value_of("controller"))
+                    .map(String::from)
+                    .or_else(|| default_controller().map(|(_, controller)| controller));
+                effective.discovery_timeout = Some(discovery::timeout().as_secs());
+                effective.json = util::json();
+
+                print_struct!(args, &effective)
+            },
+            _ => unreachable!("clap requires a config subcommand"),
+        },
+        ("status", Some(sub)) if sub.is_present("oneline") => {
+            if util::json() {
+                return Err(invalid_argument!("--oneline can't be combined with --json"));
+            }
+
+            let max_width = sub.value_of("max-width")
+                .map(|w| w.parse::<usize>().map_err(|_| invalid_argument!("--max-width must be a number of characters, got '{}'", w)))
+                .transpose()?;
+
+            let line = async {
+                let speaker = controller(&args).await?;
+                util::retry(retries, timeout, || StatusLine::new(&speaker, max_width)).await
+            }.await;
+
+            match line {
+                Ok(line) => print_struct!(args, &line),
+                Err(e) => {
+                    // a status bar only has room for one line, so that's all the error gets
+                    let message = e.to_string();
+                    let message = format!("{}{}", util::glyph(Glyph::Warning), message.lines().next().unwrap_or_default());
+                    util::output(&util::truncate(&message, max_width.unwrap_or(usize::MAX)))?;
+                    std::process::exit(1);
+                },
+            }
+        },
+        ("status", Some(sub)) if sub.is_present("waybar") => {
+            let waybar = async {
+                let speaker = controller(&args).await?;
+                util::retry(retries, timeout, || Waybar::new(&speaker)).await
+            }.await;
+
+            // the error's the module's output, exiting with an error would blank it
+            util::output(&waybar.unwrap_or_else(|e| Waybar::error(&e)).to_string())?
+        },
+        ("status", _) => {
+            let speaker = controller(&args).await?;
+
+            print_struct!(args, &util::retry(retries, timeout, || Status::new(&speaker)).await?)
+        },
+        ("clock", _) => {
+            let speaker = controller(&args).await?;
+
+            print_struct!(args, &util::retry(retries, timeout, || Clock::new(&speaker)).await?)
+        },
+        ("battery", _) => {
+            let speaker = controller(&args).await?;
+
+            print_struct!(args, &util::retry(retries, timeout, || Battery::new(&speaker)).await?)
+        },
+        ("diagnostics", _) => print_struct!(args, &Diagnostics::new(&controller(&args).await?).await),
+        ("volume", Some(sub)) => {
+            let template = format_template(sub, Volume::FIELDS)?;
+            let speaker = controller(&args).await?;
+
+            match (sub.subcommand_matches("ramp"), sub.value_of("VOLUME")) {
+                (Some(ramp), _) => {
+                    let from = volume::parse(ramp.value_of("FROM").expect("from"))?;
+                    let to = config::cap_volume(&speaker.name, volume::parse(ramp.value_of("TO").expect("to"))?);
+                    let over = ramp_duration(ramp)?;
+
+                    if !volume::ramp(&speaker, from, to, over).await? {
+                        std::process::exit(130);
+                    }
+                },
+                (None, Some(volume)) => {
+                    let volume = config::cap_volume(&speaker.name, volume.parse()?);
+
+                    util::retry(retries, timeout, || volume::set(speaker.ip, volume)).await?
+                },
+                (None, None) => {
+                    let volume = util::retry(retries, timeout, || Volume::new(&speaker)).await?;
+
+                    match template {
+                        Some(template) => util::output(&template.render(&volume, &[])?)?,
+                        None => print_struct!(args, &volume),
+                    }
+                },
+            }
+        },
+        ("fadeout", Some(sub)) => {
+            let speaker = controller(&args).await?;
+            let over = ramp_duration(sub)?;
+
+            let original = util::retry(retries, timeout, || speaker.volume()).await?;
+
+            if !volume::ramp(&speaker, original, 0, over).await? {
+                std::process::exit(130);
+            }
+
+            on_coordinator(&args, move |speaker| async move {
+                util::retry(retries, timeout, || transport::pause(speaker.ip)).await
+            }).await?;
+
+            // put the volume back while paused so the next play isn't silent
+            util::retry(retries, timeout, || volume::set(speaker.ip, original)).await?;
+
+            print_struct!(args, &Fadeout { room: speaker.name.clone(), original, target: 0 })
+        },
+        ("seek", Some(sub)) => {
+            let duration = util::hms_to_duration(sub.value_of("TIMESTAMP").expect("timestamp"))?;
+
+            on_coordinator(&args, move |speaker| async move {
+                util::retry(retries, timeout, || transport::seek(speaker.ip, duration)).await
+            }).await?
+        },
+        ("inputs", _) => print_struct!(args, &Inputs::new(discovery::discover(&cache_file, true, false).await?).await),
+        ("raw", Some(sub)) => {
+            if !sub.is_present("experimental") {
+                bail!("raw is experimental and can put your speaker into odd states, pass --experimental to use it");
+            }
+
+            let speaker = controller(&args).await?;
+            let service = sub.value_of("SERVICE").expect("service").parse::<upnp::Service>()?;
+
+            let action_args = sub.values_of("ARGS").into_iter().flatten()
+                .map(|arg| {
+                    let mut parts = arg.splitn(2, '=');
+
+                    match (parts.next(), parts.next()) {
+                        (Some(key), Some(value)) => Ok((key, value)),
+                        _ => Err(invalid_argument!("Expected key=value, got {}", arg)),
+                    }
+                })
+                .collect::<Fallible<Vec<_>>>()?;
+
+            let response = util::timeout(timeout, upnp::call(speaker.ip, service, sub.value_of("ACTION").expect("action"), &action_args)).await?;
+
+            let mut xml = Vec::new();
+            response.write(&mut xml)?;
+
+            util::output(&String::from_utf8_lossy(&xml))?;
+        },
+        ("rooms", Some(sub)) => {
+            let devices = discovery::discover(&cache_file, true, sub.is_present("invalidate")).await?;
+
+            if sub.is_present("watch") {
+                let interval = sub.value_of("interval").unwrap_or("5").parse().map(Duration::from_secs)
+                    .map_err(|_| invalid_argument!("Expected an interval in seconds"))?;
+
+                return watch_rooms(&args, devices, interval).await;
+            }
+
+            let mut topology = util::retry(retries, timeout, || Topology::new(&devices)).await?;
+
+            if sub.is_present("status") {
+                topology.fetch_status().await;
+            }
+
+            print_struct!(args, &topology)
+        },
+        _ => {
+            argparse().write_long_help(&mut std::io::stderr())?;
+            eprintln!();
+            std::process::exit(2);
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct TrackListItem {
+    pub position: u64,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    #[serde(serialize_with = "util::serialize_duration", deserialize_with = "util::deserialize_duration")]
+    pub duration: Duration,
+    pub uri: String,
+    pub album_art: Option<String>,
+}
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct TrackFilter {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub artist: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub album: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub title: Option<String>,
+}
+impl TrackFilter {
+    pub fn from_args(args: &clap::ArgMatches) -> Self {
+        Self {
+            artist: args.value_of("artist").map(String::from),
+            album: args.value_of("album").map(String::from),
+            title: args.value_of("title").map(String::from),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artist.is_none() && self.album.is_none() && self.title.is_none()
+    }
+
+    /// Case-insensitive substring match, every given filter has to match.
+    pub fn matches(&self, item: &TrackListItem) -> bool {
+        fn contains(haystack: &str, needle: &Option<String>) -> bool {
+            needle.as_ref()
+                .map(|needle| haystack.to_lowercase().contains(&needle.to_lowercase()))
+                .unwrap_or(true)
+        }
+
+        contains(&item.artist, &self.artist)
+            && contains(&item.album, &self.album)
+            && contains(&item.title, &self.title)
+    }
+}
+
+/// Serialised as the bare array of tracks it's always been, unless it was filtered or added to
+/// in which case that's included alongside the tracks.
+#[derive(Deserialize, Debug)]
+#[serde(from = "TrackListJson")]
+struct TrackList {
+    pub filters: TrackFilter,
+    pub tracks: Vec<TrackListItem>,
+    /// Items that were just added to the queue, highlighted in the list
+    pub inserted: Option<queue::Enqueued>,
+    pub show_uris: bool,
+    /// Columns to show them in with `--table`
+    pub table: Option<Vec<&'static str>>,
+}
+impl serde::Serialize for TrackList {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        #[derive(Serialize)]
+        struct Fields<'a> {
+            filters: &'a TrackFilter,
+            tracks: &'a [TrackListItem],
+            #[serde(skip_serializing_if = "Option::is_none")]
+            inserted: Option<&'a queue::Enqueued>,
+        }
+
+        if self.filters.is_empty() && self.inserted.is_none() {
+            serde::Serialize::serialize(&self.tracks, serializer)
+        } else {
+            serde::Serialize::serialize(&Fields { filters: &self.filters, tracks: &self.tracks, inserted: self.inserted.as_ref() }, serializer)
+        }
+    }
+}
+
+/// Either shape a `TrackList` is written in.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum TrackListJson {
+    Tracks(Vec<TrackListItem>),
+    Fields {
+        #[serde(default)]
+        filters: TrackFilter,
+        tracks: Vec<TrackListItem>,
+        #[serde(default)]
+        inserted: Option<queue::Enqueued>,
+    },
+}
+impl From<TrackListJson> for TrackList {
+    fn from(json: TrackListJson) -> Self {
+        let (filters, tracks, inserted) = match json {
+            TrackListJson::Tracks(tracks) => (TrackFilter::default(), tracks, None),
+            TrackListJson::Fields { filters, tracks, inserted } => (filters, tracks, inserted),
+        };
+
+        TrackList { filters, tracks, inserted, show_uris: false, table: None }
+    }
+}
+impl TrackList {
+    pub const COLUMNS: &'static [&'static str] = &["pos", "title", "artist", "album", "duration", "uri"];
+    pub const DEFAULT_COLUMNS: &'static [&'static str] = &["pos", "title", "artist", "album", "duration"];
+
+    pub async fn new(speaker: &Speaker, filters: TrackFilter) -> Fallible<Self> {
+        let tracks = queue::list(speaker).await?
+            .into_iter()
+            .map(|v| TrackListItem {
+                position: v.position,
+                title: v.title,
+                artist: v.artist,
+                album: v.album,
+                duration: v.duration,
+                uri: v.uri,
+                album_art: v.album_art,
+            })
+            .filter(|item| filters.matches(item))
+            .collect();
+
+        Ok(Self { filters, tracks, inserted: None, show_uris: false, table: None })
+    }
+}
+impl std::fmt::Display for TrackList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let inserted = |position| self.inserted.as_ref()
+            .is_some_and(|i| position >= i.position && position < i.position + i.added);
+
+        if let Some(columns) = &self.table {
+            let table = util::Table {
+                headers: columns.iter().map(|c| c.to_uppercase()).collect(),
+                rows: self.tracks.iter()
+                    .map(|item| columns.iter()
+                        .map(|column| match *column {
+                            "pos" => format!("{}{}", if inserted(item.position) { "+" } else { "" }, item.position),
+                            "title" => item.title.clone(),
+                            "artist" => item.artist.clone(),
+                            "album" => item.album.clone(),
+                            "duration" => util::duration_to_hms(item.duration),
+                            "uri" => item.uri.clone(),
+                            column => unreachable!("unknown column {}", column),
+                        })
+                        .collect())
+                    .collect(),
+            };
+
+            return write!(f, "{}", table);
+        }
+
+        for item in &self.tracks {
+            write!(f, "{}{}: {} - {} ({})",
+                   if inserted(item.position) { util::paint(util::Style::Green, "+ ") } else { String::new() },
+                   item.position,
+                   item.artist,
+                   item.title,
+                   util::duration_to_hms(item.duration))?;
+
+            if self.show_uris {
+                write!(f, " {}", item.uri)?;
+            }
+
+            writeln!(f)?
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Track {
+    pub title: String,
+    pub artist: String,
+    pub album: Option<String>,
+    #[serde(serialize_with = "util::serialize_duration", deserialize_with = "util::deserialize_duration")]
+    pub running_time: Duration,
+    #[serde(serialize_with = "util::serialize_duration", deserialize_with = "util::deserialize_duration")]
+    pub duration: Duration
+}
+impl Track {
+    /// Placeholders `--format` can use.
+    pub const FIELDS: &'static [&'static str] = &["title", "artist", "album", "running_time", "duration", "elapsed", "percent", "state"];
+
+    pub async fn new(speaker: &Speaker) -> Fallible<Track> {
+        let track = speaker.track().await?;
+
+        Ok(Self {
+            title: track.title,
+            artist: track.artist,
+            album: track.album,
+            running_time: track.running_time,
+            duration: track.duration
+        })
+    }
+
+    /// How far through the track playback is, 0-100. Streams have no duration, so they're always
+    /// at 0.
+    pub fn percent_played(&self) -> u64 {
+        match self.duration.as_secs() {
+            0 => 0,
+            duration => (self.running_time.as_secs() * 100 / duration).min(100),
+        }
+    }
+}
+impl std::fmt::Display for Track {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}{}", util::glyph(Glyph::Artist), util::paint(util::Style::Cyan, &self.artist))?;
+        writeln!(f, "{}{}", util::glyph(Glyph::Title), util::paint(util::Style::Bold, &self.title))?;
+
+        if let Some(album) = &self.album {
+            writeln!(f, "{}{}", util::glyph(Glyph::Album), album)?;
+        }
+
+        let running_time = util::duration_to_hms(self.running_time);
+        let duration = util::duration_to_hms(self.duration);
+
+        write!(f, "{}{}/{}", util::glyph(Glyph::Elapsed), running_time, duration)?;
+
+        const PROG_BAR_LEN: usize = 25;
+        write!(f, " {}", util::progress_bar(self.percent_played() as usize * PROG_BAR_LEN / 100, PROG_BAR_LEN))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Status {
+    pub room: String,
+    pub state: String,
+    pub play_mode: String,
+    pub track: Track,
+    pub volume: Volume,
+    pub group: Group,
+}
+impl Status {
+    pub async fn new(speaker: &Speaker) -> Fallible<Status> {
+        let (track, volume, state, play_mode, group) = futures::try_join!(
+            Track::new(speaker),
+            Volume::new(speaker),
+            transport::state(speaker.ip),
+            transport::play_mode(speaker.ip),
+            topology::group_of(speaker),
+        )?;
+
+        Ok(Self {
+            room: speaker.name.clone(),
+            state,
+            play_mode,
+            track,
+            volume,
+            group: Group::try_from(&group)?,
+        })
+    }
+}
+impl std::fmt::Display for Status {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{} ({}, {})", self.room, self.state, self.play_mode)?;
+        writeln!(f, "{}", self.track)?;
+        writeln!(f, "{}", self.volume)?;
+        write!(f, "{}", self.group)
+    }
+}
+
+/// What's playing and how loud, on one line for `status --oneline`.
+#[derive(Serialize, Deserialize, Debug)]
+struct StatusLine {
+    pub state: String,
+    pub track: Track,
+    pub volume: Volume,
+    #[serde(skip)]
+    pub max_width: Option<usize>,
+}
+impl StatusLine {
+    /// Everything's fetched at once, it's meant to be polled every couple of seconds.
+    pub async fn new(speaker: &Speaker, max_width: Option<usize>) -> Fallible<StatusLine> {
+        let (state, track, volume) = futures::try_join!(
+            transport::state(speaker.ip),
+            Track::new(speaker),
+            Volume::new(speaker),
+        )?;
+
+        Ok(Self { state, track, volume, max_width })
+    }
+
+    /// Nothing's loaded, or it's stopped, there's nothing worth showing.
+    pub fn idle(&self) -> bool {
+        self.state == "STOPPED" || (self.track.title.is_empty() && self.track.artist.is_empty())
+    }
+
+    /// `artist – title`, or whichever of them there is.
+    pub fn playing(&self) -> String {
+        match (self.track.artist.as_str(), self.track.title.as_str()) {
+            ("", title) => title.to_string(),
+            (artist, "") => artist.to_string(),
+            (artist, title) => format!("{} {} {}", artist, util::glyph(Glyph::Dash), title),
+        }
+    }
+}
+impl std::fmt::Display for StatusLine {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let max_width = self.max_width.unwrap_or(usize::MAX);
+
+        if self.idle() {
+            return write!(f, "{}", util::truncate(util::glyph(Glyph::LongDash), max_width));
+        }
+
+        let state = util::glyph(if self.state == "PAUSED_PLAYBACK" { Glyph::Pause } else { Glyph::Play });
+
+        // streams have no duration, so only how long they've been playing is shown
+        let time = match self.track.duration.as_secs() {
+            0 => util::duration_to_short(self.track.running_time),
+            _ => format!("{}/{}", util::duration_to_short(self.track.running_time), util::duration_to_short(self.track.duration)),
+        };
+
+        let volume = if self.volume.muted { "muted".to_string() } else { format!("{}%", self.volume.volume) };
+
+        // the title is the only part that's shortened, everything else is already short
+        let suffix = format!(" {} {}{}", time, util::glyph(Glyph::Note), volume);
+        let room = max_width.saturating_sub(state.chars().count() + 1 + suffix.chars().count());
+
+        write!(f, "{} {}{}", state, util::truncate(&self.playing(), room), suffix)
+    }
+}
+
+/// `status --waybar`, the object Waybar's custom modules read with `"return-type": "json"`.
+#[derive(Serialize, Deserialize, Debug)]
+struct Waybar {
+    pub text: String,
+    pub tooltip: String,
+    /// `playing`, `paused`, `stopped` or `error`, for styling the module
+    pub class: String,
+    /// How far through the track playback is
+    pub percentage: u64,
+}
+impl Waybar {
+    pub async fn new(speaker: &Speaker) -> Fallible<Waybar> {
+        let (status, queue) = futures::try_join!(StatusLine::new(speaker, None), QueuePosition::new(speaker))?;
+
+        let class = match status.state.as_str() {
+            _ if status.idle() => "stopped",
+            "PAUSED_PLAYBACK" => "paused",
+            _ => "playing",
+        };
+
+        let mut tooltip = vec![speaker.name.clone()];
+
+        if !status.idle() {
+            tooltip.push(status.playing());
+        }
+
+        if let Some(album) = status.track.album.as_ref().filter(|a| !a.is_empty()) {
+            tooltip.push(format!("Album: {}", album));
+        }
+
+        tooltip.push(match queue.position {
+            Some(position) => format!("Queue: {}/{}", position, queue.total),
+            None => format!("Queue: {} tracks, not playing from it", queue.total),
+        });
+        tooltip.push(match status.volume.muted {
+            true => "Volume: muted".to_string(),
+            false => format!("Volume: {}%", status.volume.volume),
+        });
+
+        Ok(Waybar {
+            text: if status.idle() { util::glyph(Glyph::LongDash).to_string() } else { status.playing() },
+            tooltip: tooltip.join("\n"),
+            class: class.to_string(),
+            percentage: if status.idle() { 0 } else { status.track.percent_played() },
+        })
+    }
+
+    /// Waybar drops modules that print anything it can't parse, so failures are shown in the
+    /// module instead.
+    pub fn error(e: &failure::Error) -> Waybar {
+        Waybar {
+            text: util::glyph(Glyph::Warning).trim_end().to_string(),
+            tooltip: e.to_string(),
+            class: "error".to_string(),
+            percentage: 0,
+        }
+    }
+}
+impl std::fmt::Display for Waybar {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        // Waybar reads one object per line, so this is JSON whether or not --json was given
+        write!(f, "{}", serde_json::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Volume {
+    volume: u8,
+    muted: bool,
+}
+impl Volume {
+    /// Placeholders `--format` can use.
+    pub const FIELDS: &'static [&'static str] = &["volume", "muted"];
+
+    pub async fn new(speaker: &Speaker) -> Result<Volume, failure::Error> {
+        Ok(Self {
+            volume: speaker.volume().await?,
+            muted: speaker.muted().await?,
+        })
+    }
+}
+impl std::fmt::Display for Volume {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const MAX_VOLUME: usize = 100;
+        const PROG_BAR_LEN: usize = 25;
+
+        let pictogram = util::glyph(if self.muted { Glyph::Muted } else { Glyph::Volume });
+        let volume = format!("{}{}/{}", pictogram, self.volume, MAX_VOLUME);
+
+        write!(f, "{}", if self.muted { util::paint(util::Style::Red, &volume) } else { volume })?;
+
+        let percent = (self.volume as usize * PROG_BAR_LEN) / MAX_VOLUME;
+
+        write!(f, " {}", util::progress_bar(percent, PROG_BAR_LEN))
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Info {
+    pub ip: IpAddr,
+    pub model: String,
+    pub model_number: String,
+    pub software_version: String,
+    pub hardware_version: String,
+    pub serial_number: String,
+    pub name: String,
+    pub uuid: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub network: Option<NetworkInfo>,
+}
+impl Info {
+    /// Placeholders `--format` can use.
+    pub const FIELDS: &'static [&'static str] = &["ip", "model", "model_number", "software_version", "hardware_version", "serial_number", "name", "uuid"];
+
+    pub fn new(speaker: &Speaker) -> Info {
+        Info {
+            ip: speaker.ip,
+            model: speaker.model.clone(),
+            model_number: speaker.model_number.clone(),
+            software_version: speaker.software_version.clone(),
+            hardware_version: speaker.hardware_version.clone(),
+            serial_number: speaker.serial_number.clone(),
+            name: speaker.name.clone(),
+            uuid: speaker.uuid.clone(),
+            network: None,
+        }
+    }
+}
+impl std::fmt::Display for Info {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", util::heading(Glyph::Room, &self.name))?;
+
+        writeln!(f, "Model: {} ({})", self.model, self.model_number)?;
+
+        writeln!(f, "Versions: Software {}, Hardware {}", self.software_version, self.hardware_version)?;
+        writeln!(f, "Serial number: {}", self.serial_number)?;
+        writeln!(f, "UUID: {}", self.uuid)?;
+
+        if let Some(network) = &self.network {
+            writeln!(f)?;
+            write!(f, "{}", network)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct NetworkInfo {
+    pub wired: Option<bool>,
+    pub ssid: Option<String>,
+    pub rssi: Option<i32>,
+}
+impl NetworkInfo {
+    /// Pulled from the speaker's status pages, anything it doesn't report is left as `None`.
+    pub async fn new(speaker: &Speaker) -> NetworkInfo {
+        let (wired, wireless) = futures::join!(status::wired(speaker.ip), status::wireless(speaker.ip));
+        let wireless = wireless.unwrap_or_default();
+
+        NetworkInfo {
+            wired: wired.ok(),
+            ssid: wireless.ssid,
+            rssi: wireless.rssi,
+        }
+    }
+}
+impl std::fmt::Display for NetworkInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "Connection: {}", match self.wired {
+            Some(true) => "Wired",
+            Some(false) => "Wireless",
+            None => "Unknown",
+        })?;
+        writeln!(f, "SSID: {}", self.ssid.as_deref().unwrap_or("Unknown"))?;
+
+        match self.rssi {
+            Some(rssi) => writeln!(f, "Signal strength (RSSI): {}", rssi),
+            None => writeln!(f, "Signal strength (RSSI): Unknown"),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BondedDevice {
+    pub name: String,
+    pub ip: IpAddr,
+    pub uuid: String,
+    pub role: Option<String>,
+}
+
+/// Field names of `RoomInfo`, `Group` and `Topology` make up the `rooms --json` output that
+/// scripts rely on, so they mustn't be renamed.
+#[derive(Serialize, Deserialize, Debug)]
+struct RoomInfo {
+    pub name: String,
+    pub ip: IpAddr,
+    pub uuid: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub bonded: Vec<BondedDevice>,
+}
+impl From<&Speaker> for RoomInfo {
+    fn from(speaker: &Speaker) -> Self {
+        Self {
+            name: speaker.name.clone(),
+            ip: speaker.ip,
+            uuid: speaker.uuid.clone(),
+            bonded: Vec::new(),
+        }
+    }
+}
+impl RoomInfo {
+    pub fn new(group: &topology::ZoneGroup, member: &topology::ZoneMember) -> Self {
+        let mut bonded = group.bonded(member)
+            .into_iter()
+            .map(|b| BondedDevice {
+                name: b.name.clone(),
+                ip: b.ip,
+                uuid: b.uuid.clone(),
+                role: member.role_of(&b.uuid)
+                    .or_else(|| b.role_of(&b.uuid))
+                    .map(String::from),
+            })
+            .collect::<Vec<_>>();
+
+        // bonded devices usually share the room's name, the role tells them apart
+        bonded.sort_by(|a, b| (&a.name, &a.role, &a.uuid).cmp(&(&b.name, &b.role, &b.uuid)));
+
+        Self {
+            name: member.name.clone(),
+            ip: member.ip,
+            uuid: member.uuid.clone(),
+            bonded,
+        }
+    }
+}
+
+impl std::fmt::Display for RoomInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+
+        for (i, bonded) in self.bonded.iter().enumerate() {
+            let branch = util::glyph(if i == self.bonded.len() - 1 { Glyph::LastBranch } else { Glyph::Branch });
+            write!(f, "\n{} [bonded] {}", branch, bonded.name)?;
+
+            if let Some(role) = &bonded.role {
+                write!(f, " ({})", role)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct GroupStatus {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub state: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub now_playing: Option<transport::NowPlaying>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<u8>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+impl GroupStatus {
+    /// Never fails, an unreachable coordinator is reported through `error` so it doesn't take
+    /// down the rest of the listing.
+    pub async fn new(coordinator: IpAddr) -> GroupStatus {
+        let status = futures::try_join!(
+            transport::state(coordinator),
+            transport::now_playing(coordinator),
+            topology::group_volume(coordinator),
+        );
+
+        match status {
+            Ok((state, now_playing, volume)) => GroupStatus {
+                state: Some(state),
+                now_playing,
+                volume: Some(volume),
+                error: None,
+            },
+            Err(e) => GroupStatus {
+                error: Some(e.to_string()),
+                ..GroupStatus::default()
+            },
+        }
+    }
+}
+impl std::fmt::Display for GroupStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if let Some(error) = &self.error {
+            return write!(f, "{}unreachable ({})", util::glyph(Glyph::Warning), error);
+        }
+
+        match (self.state.as_deref(), &self.now_playing) {
+            (Some("PLAYING"), Some(track)) | (Some("TRANSITIONING"), Some(track)) =>
+                write!(f, "{}{} {} {}", util::glyph(Glyph::Playing), track.artist, util::glyph(Glyph::Dash), track.title)?,
+            (Some("PAUSED_PLAYBACK"), Some(track)) =>
+                write!(f, "{}{} {} {}", util::glyph(Glyph::Paused), track.artist, util::glyph(Glyph::Dash), track.title)?,
+            _ => write!(f, "idle")?,
+        }
+
+        if let Some(volume) = self.volume {
+            write!(f, " {}{}", util::glyph(Glyph::Volume), volume)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Group {
+    pub coordinator: RoomInfo,
+    pub members: Vec<RoomInfo>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    pub status: Option<GroupStatus>,
+}
+impl TryFrom<&topology::ZoneGroup> for Group {
+    type Error = failure::Error;
+
+    fn try_from(group: &topology::ZoneGroup) -> Fallible<Self> {
+        let coordinator = group.coordinator()
+            .or_else(|| group.members.first())
+            .ok_or_else(|| format_err!("Group {} doesn't have any members", group.coordinator))?;
+
+        let mut members = group.members.iter()
+            .filter(|m| !m.invisible)
+            .map(|m| RoomInfo::new(group, m))
+            .collect::<Vec<_>>();
+        members.sort_by(|a, b| (&a.name, &a.uuid).cmp(&(&b.name, &b.uuid)));
+
+        Ok(Self {
+            coordinator: RoomInfo::new(group, coordinator),
+            members,
+            status: None,
+        })
+    }
+}
+impl std::fmt::Display for Group {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.coordinator.name)?;
+
+        if let Some(status) = &self.status {
+            write!(f, " {} {}", util::glyph(Glyph::LongDash), status)?;
+        }
+
+        for (i, member) in self.members.iter().enumerate() {
+            let last = i == self.members.len() - 1;
+
+            write!(f, "\n{} {}", util::glyph(if last { Glyph::LastBranch } else { Glyph::Branch }), member.name)?;
+
+            if member.uuid == self.coordinator.uuid {
+                write!(f, " (coordinator)")?;
+            }
+
+            for (j, bonded) in member.bonded.iter().enumerate() {
+                write!(f, "\n{}  {} [bonded] {}",
+                       if last { " " } else { util::glyph(Glyph::Trunk) },
+                       util::glyph(if j == member.bonded.len() - 1 { Glyph::LastBranch } else { Glyph::Branch }),
+                       bonded.name)?;
+
+                if let Some(role) = &bonded.role {
+                    write!(f, " ({})", role)?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Topology(Vec<Group>);
+impl Topology {
+    /// Built from a single topology query, bonded devices are listed under the room they're
+    /// part of rather than as rooms of their own.
+    pub async fn new(devices: &[Speaker]) -> Fallible<Self> {
+        Self::from_groups(&topology::household_groups(devices).await?)
+    }
+
+    fn from_groups(groups: &[topology::ZoneGroup]) -> Fallible<Self> {
+        let mut groups = groups.iter()
+            .filter(|g| g.members.iter().any(|m| !m.invisible))
+            .map(Group::try_from)
+            .collect::<Fallible<Vec<_>>>()?;
+
+        // sorted so repeated runs diff cleanly
+        groups.sort_by(|a, b| (&a.coordinator.name, &a.coordinator.uuid).cmp(&(&b.coordinator.name, &b.coordinator.uuid)));
+
+        Ok(Self(groups))
+    }
+
+    /// Fetch what every group is playing, concurrently so this doesn't take N round trips.
+    pub async fn fetch_status(&mut self) {
+        futures::future::join_all(self.0.iter_mut().map(|group| async move {
+            group.status = Some(GroupStatus::new(group.coordinator.ip).await);
+        })).await;
+    }
+}
+impl std::fmt::Display for Topology {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, group) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", group)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(rename_all = "snake_case")]
+enum JoinStatus {
+    Joined,
+    AlreadyGrouped,
+    Failed,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct JoinResult {
+    pub room: String,
+    pub status: JoinStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct PartyResult(Vec<JoinResult>);
+impl PartyResult {
+    /// Join every visible speaker in the household, bar the `except`ed rooms, to the group of the
+    /// given speaker.
+    pub async fn new(cache_file: &std::path::Path, speaker: &Speaker, except: Vec<&str>) -> Fallible<Self> {
+        let groups = topology::zone_groups(speaker).await?;
+        let group = groups.iter()
+            .find(|g| g.contains(&speaker.uuid))
+            .ok_or_else(|| format_err!("{} isn't part of any group", speaker.name))?;
+
+        let invisible = groups.iter()
+            .flat_map(|g| g.members.iter())
+            .filter(|m| m.invisible)
+            .map(|m| m.uuid.as_str())
+            .collect::<Vec<_>>();
+
+        let devices = discovery::discover(cache_file, true, false).await?;
+
+        let results = futures::future::join_all(devices.iter()
+            .filter(|d| d.uuid != group.coordinator && !invisible.contains(&d.uuid.as_str()))
+            .filter(|d| !except.iter().any(|e| e.trim().eq_ignore_ascii_case(&d.name)))
+            .map(|device| async move {
+                let (status, error) = if group.contains(&device.uuid) {
+                    (JoinStatus::AlreadyGrouped, None)
+                } else {
+                    match topology::join(device, &group.coordinator).await {
+                        Ok(()) => (JoinStatus::Joined, None),
+                        Err(e) => (JoinStatus::Failed, Some(e.to_string())),
+                    }
+                };
+
+                JoinResult { room: device.name.clone(), status, error }
+            })).await;
+
+        Ok(Self(results))
+    }
+}
+impl std::fmt::Display for PartyResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let width = self.0.iter().map(|r| r.room.len()).max().unwrap_or(0);
+
+        for (i, result) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{:width$}  ", result.room, width = width)?;
+
+            match (&result.status, &result.error) {
+                (JoinStatus::Joined, _) => write!(f, "joined")?,
+                (JoinStatus::AlreadyGrouped, _) => write!(f, "already grouped")?,
+                (JoinStatus::Failed, Some(error)) => write!(f, "failed: {}", error)?,
+                (JoinStatus::Failed, None) => write!(f, "failed")?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct BatteryDiagnostics {
+    pub level: Option<u8>,
+    pub health: Option<String>,
+    pub temperature: Option<String>,
+    pub power_source: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Diagnostics {
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "util::serialize_optional_duration", deserialize_with = "util::deserialize_optional_duration")]
+    pub uptime: Option<Duration>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wifi_rssi: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub battery: Option<BatteryDiagnostics>,
+}
+impl Diagnostics {
+    /// Not every speaker serves every status page, anything we can't fetch is left out.
+    pub async fn new(speaker: &Speaker) -> Diagnostics {
+        let (uptime, wireless, battery) = futures::join!(
+            status::uptime(speaker.ip),
+            status::wireless(speaker.ip),
+            status::battery(speaker.ip),
+        );
+
+        Diagnostics {
+            uptime: uptime.ok(),
+            wifi_rssi: wireless.ok().and_then(|w| w.rssi),
+            battery: battery.ok().flatten().map(|b| BatteryDiagnostics {
+                level: b.level,
+                health: b.health,
+                temperature: b.temperature,
+                power_source: b.power_source,
+            }),
+        }
+    }
+}
+impl std::fmt::Display for Diagnostics {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "System")?;
+        match self.uptime {
+            Some(uptime) => writeln!(f, "  Uptime: {}", util::duration_to_hms(uptime))?,
+            None => writeln!(f, "  Uptime: unknown")?,
+        }
+
+        writeln!(f, "Network")?;
+        match self.wifi_rssi {
+            Some(rssi) => write!(f, "  WiFi signal (RSSI): {}", rssi)?,
+            None => write!(f, "  WiFi signal (RSSI): unavailable")?,
+        }
+
+        if let Some(battery) = &self.battery {
+            write!(f, "\nBattery")?;
+
+            if let Some(level) = battery.level {
+                write!(f, "\n  Level: {}%", level)?;
+            }
+            if let Some(health) = &battery.health {
+                write!(f, "\n  Health: {}", health)?;
+            }
+            if let Some(temperature) = &battery.temperature {
+                write!(f, "\n  Temperature: {}", temperature)?;
+            }
+            if let Some(power_source) = &battery.power_source {
+                write!(f, "\n  Power source: {}", power_source)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SplitResult {
+    pub room: String,
+    pub group: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct UngroupResult(Vec<SplitResult>);
+impl UngroupResult {
+    /// Take every member out of its group, or just the members of the group containing `room`.
+    /// Coordinators stay put since they become standalone once their members have left, and
+    /// bonded devices are left alone as they're part of a single room rather than a group.
+    pub async fn new(cache_file: &std::path::Path, room: Option<&str>) -> Fallible<Self> {
+        let devices = discovery::discover(cache_file, true, false).await?;
+
+        let groups = topology::household_groups(&devices).await?
+            .into_iter()
+            .filter(|g| match room {
+                Some(room) => g.members.iter().any(|m| !m.invisible && m.name.eq_ignore_ascii_case(room)),
+                None => true,
+            })
+            .collect::<Vec<_>>();
+
+        if let (Some(room), true) = (room, groups.is_empty()) {
+            return Err(not_found!("Couldn't find a room named {}", room));
+        }
+
+        let mut results = Vec::new();
+
+        for group in &groups {
+            let coordinator = group.coordinator().map(|c| c.name.clone()).unwrap_or_default();
+
+            for member in group.members.iter().filter(|m| !m.invisible && m.uuid != group.coordinator) {
+                let result = match devices.iter().find(|d| d.uuid == member.uuid) {
+                    Some(device) => topology::leave(device).await,
+                    None => match Speaker::from_ip(member.ip).await {
+                        Ok(device) => topology::leave(&device).await,
+                        Err(e) => Err(e),
+                    },
+                };
+
+                results.push(SplitResult {
+                    room: member.name.clone(),
+                    group: coordinator.clone(),
+                    error: result.err().map(|e| e.to_string()),
+                });
+            }
+        }
+
+        Ok(Self(results))
+    }
+}
+impl std::fmt::Display for UngroupResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "Every room is already on its own");
+        }
+
+        for (i, result) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            match &result.error {
+                None => write!(f, "{}: split from {}", result.room, result.group)?,
+                Some(error) => write!(f, "{}: failed to split from {}: {}", result.room, result.group, error)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Battery {
+    pub percent: u8,
+    pub charging: bool,
+    pub temperature: Option<String>,
+}
+impl Battery {
+    pub async fn new(speaker: &Speaker) -> Fallible<Battery> {
+        // speakers without a battery either don't serve the page or leave the status out
+        let status = status::battery(speaker.ip).await.ok().flatten()
+            .ok_or_else(|| format_err!("This speaker has no battery"))?;
+
+        Ok(Battery {
+            percent: status.level.ok_or_else(|| format_err!("Speaker didn't report its battery level"))?,
+            charging: status.power_source.map(|s| s != "BATTERY").unwrap_or(false),
+            temperature: status.temperature,
+        })
+    }
+}
+impl std::fmt::Display for Battery {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const PROG_BAR_LEN: usize = 25;
+
+        write!(f, "{}{}% ({})", util::glyph(Glyph::Battery), self.percent, if self.charging { "charging" } else { "discharging" })?;
+
+        let percent = (self.percent.min(100) as usize * PROG_BAR_LEN) / 100;
+        write!(f, " {}", util::progress_bar(percent, PROG_BAR_LEN))?;
+
+        if let Some(temperature) = &self.temperature {
+            write!(f, "\nTemperature: {}", temperature)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct QueuePosition {
+    pub position: Option<u64>,
+    pub total: u64,
+}
+impl QueuePosition {
+    pub async fn new(speaker: &Speaker) -> Fallible<QueuePosition> {
+        let (position, total) = futures::try_join!(queue::position(speaker), queue::length(speaker))?;
+
+        Ok(QueuePosition {
+            position: position.map(|p| p.track),
+            total,
+        })
+    }
+}
+impl std::fmt::Display for QueuePosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.position {
+            Some(position) => write!(f, "{}/{}", position, self.total),
+            None => write!(f, "Not playing from the queue ({} tracks queued)", self.total),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Input {
+    pub room: String,
+    pub ip: IpAddr,
+    pub uuid: String,
+    pub line_in: bool,
+    pub tv: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Inputs(Vec<Input>);
+impl Inputs {
+    /// Probe the device description of every speaker for the AudioIn (line-in) and HTControl
+    /// (TV) services, speakers we can't reach are left out.
+    pub async fn new(devices: Vec<Speaker>) -> Inputs {
+        const LINE_IN: &str = "urn:schemas-upnp-org:service:AudioIn:1";
+        const TV: &str = "urn:schemas-upnp-org:service:HTControl:1";
+
+        let probes = futures::future::join_all(devices.iter().map(|d| upnp::services(d.ip))).await;
+
+        let mut inputs = devices.iter()
+            .zip(probes)
+            .filter_map(|(device, services)| {
+                let services = services.ok()?;
+
+                Some(Input {
+                    room: device.name.clone(),
+                    ip: device.ip,
+                    uuid: device.uuid.clone(),
+                    line_in: services.iter().any(|s| s == LINE_IN),
+                    tv: services.iter().any(|s| s == TV),
+                })
+            })
+            .filter(|input| input.line_in || input.tv)
+            .collect::<Vec<_>>();
+        inputs.sort_by(|a, b| a.room.cmp(&b.room));
+
+        Inputs(inputs)
+    }
+}
+impl std::fmt::Display for Inputs {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No speakers with a line-in or TV input");
+        }
+
+        for (i, input) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let kinds = [(input.line_in, "line-in"), (input.tv, "tv")].iter()
+                .filter(|(present, _)| *present)
+                .map(|(_, kind)| *kind)
+                .collect::<Vec<_>>();
+
+            write!(f, "{}: {}", input.room, kinds.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct StereoPair {
+    pub name: String,
+    pub left: RoomInfo,
+    pub right: RoomInfo,
+    pub separated: bool,
+}
+impl std::fmt::Display for StereoPair {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.separated {
+            writeln!(f, "Separated the {} stereo pair, both speakers are rooms of their own again:", self.name)?;
+        } else {
+            writeln!(f, "Created the {} stereo pair:", self.name)?;
+        }
+
+        writeln!(f, "Left:  {} ({}, {})", self.left.name, self.left.ip, self.left.uuid)?;
+        write!(f, "Right: {} ({}, {})", self.right.name, self.right.ip, self.right.uuid)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Transfer {
+    pub from: String,
+    pub to: String,
+    pub uri: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tracks: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub track: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none", serialize_with = "util::serialize_optional_duration", deserialize_with = "util::deserialize_optional_duration")]
+    pub elapsed: Option<Duration>,
+    pub play_mode: String,
+    pub playing: bool,
+}
+impl Transfer {
+    /// Snapshot whatever the coordinator of `from`'s group is playing, stop it and recreate it on
+    /// `to`. Queues are copied over item by item and resumed at the same track and time, anything
+    /// else (radio, line-in, ...) is moved by loading the same transport URI.
+    pub async fn new(from: &Speaker, to: &Speaker, force: bool) -> Fallible<Transfer> {
+        let (from_group, to_group) = futures::try_join!(topology::group_of(from), topology::group_of(to))?;
+
+        if from_group.coordinator == to_group.coordinator {
+            bail!("{} and {} are already playing the same thing", from.name, to.name);
+        }
+
+        let grouped_with = to_group.members.iter()
+            .filter(|m| !m.invisible && m.uuid != to.uuid)
+            .map(|m| m.name.as_str())
+            .collect::<Vec<_>>();
+
+        if !grouped_with.is_empty() {
+            if !force {
+                bail!("{} is grouped with {}, pass --force to take it out of its group", to.name, grouped_with.join(", "));
+            }
+
+            topology::leave(to).await?;
+        }
+
+        // playback state lives on the coordinator, which might not be the room we were given
+        let source = match from_group.coordinator() {
+            Some(c) if c.uuid != from.uuid => Speaker::from_ip(c.ip).await?,
+            _ => Speaker::from_ip(from.ip).await?,
+        };
+
+        let (media, state, play_mode) = futures::try_join!(
+            transport::media_info(source.ip),
+            transport::state(source.ip),
+            transport::play_mode(source.ip),
+        )?;
+
+        if media.uri.is_empty() {
+            bail!("Nothing is loaded on {}", from.name);
+        }
+
+        let playing = state == "PLAYING";
+
+        let mut transfer = Transfer {
+            from: from.name.clone(),
+            to: to.name.clone(),
+            uri: media.uri.clone(),
+            tracks: None,
+            track: None,
+            elapsed: None,
+            play_mode: play_mode.clone(),
+            playing,
+        };
+
+        if media.uri.starts_with("x-rincon-queue:") {
+            let (items, position) = futures::try_join!(queue::list(&source), queue::position(&source))?;
+
+            if playing {
+                transport::stop(source.ip).await?;
+            }
+
+            queue::replace(to, items.iter().map(|item| (item.uri.as_str(), item.metadata.as_str()))).await?;
+            queue::use_queue(to).await?;
+            transport::set_play_mode(to.ip, &play_mode).await?;
+
+            if let Some(position) = position {
+                transport::skip_to(to.ip, position.track).await?;
+                transport::seek(to.ip, position.elapsed).await?;
+
+                transfer.track = Some(position.track);
+                transfer.elapsed = Some(position.elapsed);
+            }
+
+            transfer.tracks = Some(items.len() as u64);
+            transfer.uri = format!("x-rincon-queue:{}#0", to.uuid);
+        } else {
+            if playing {
+                transport::stop(source.ip).await?;
+            }
+
+            transport::set_uri(to.ip, &media.uri, &media.metadata).await?;
+        }
+
+        if playing {
+            transport::play(to.ip).await?;
+        }
+
+        Ok(transfer)
+    }
+}
+impl std::fmt::Display for Transfer {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self.tracks {
+            Some(tracks) => write!(f, "Moved the queue ({} tracks) from {} to {}", tracks, self.from, self.to)?,
+            None => write!(f, "Moved {} from {} to {}", self.uri, self.from, self.to)?,
+        }
+
+        if let (Some(track), Some(elapsed)) = (self.track, self.elapsed) {
+            write!(f, "\nResumed at track {} ({})", track, util::duration_to_hms(elapsed))?;
+        }
+
+        write!(f, "\nPlay mode: {}", self.play_mode)?;
+
+        if !self.playing {
+            write!(f, "\n{} wasn't playing so {} has been left paused", self.from, self.to)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotSummary {
+    pub name: String,
+    pub created: String,
+    pub rooms: usize,
+    pub groups: usize,
+}
+impl From<&snapshot::Snapshot> for SnapshotSummary {
+    fn from(snapshot: &snapshot::Snapshot) -> Self {
+        Self {
+            name: snapshot.name.clone(),
+            created: snapshot.created.clone(),
+            rooms: snapshot.speakers.len(),
+            groups: snapshot.groups.len(),
+        }
+    }
+}
+impl std::fmt::Display for SnapshotSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}: {} rooms in {} groups ({})", self.name, self.rooms, self.groups, self.created)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotList(Vec<SnapshotSummary>);
+impl std::fmt::Display for SnapshotList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No snapshots saved");
+        }
+
+        for (i, snapshot) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}", snapshot)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct SnapshotRestore {
+    pub name: String,
+    pub groups: usize,
+    pub missing: Vec<String>,
+}
+impl std::fmt::Display for SnapshotRestore {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Restored {} groups from the {} snapshot", self.groups, self.name)?;
+
+        if !self.missing.is_empty() {
+            write!(f, "\nCouldn't find {}, skipped", self.missing.join(", "))?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ControllerResult {
+    pub room: String,
+    pub ok: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub volume: Option<u8>,
+}
+impl ControllerResult {
+    fn new(room: String, result: Fallible<Option<u8>>) -> Self {
+        match result {
+            Ok(volume) => Self { room, ok: true, error: None, volume },
+            Err(e) => Self { room, ok: false, error: Some(e.to_string()), volume: None },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct MultiResult(Vec<ControllerResult>);
+impl MultiResult {
+    /// Run the subcommand against every speaker given with `-c`, or every room with `--all`.
+    /// Commands that act on a whole group (playback, seeking, skipping tracks) are only sent once
+    /// to each group's coordinator however many of its rooms were given.
+    pub async fn new(args: &clap::ArgMatches<'_>, retries: u32, timeout: Duration) -> Fallible<MultiResult> {
+        use futures::stream::StreamExt;
+
+        const MAX_IN_FLIGHT: usize = 8;
+
+        let group_level = match args.subcommand() {
+            ("play", _) | ("pause", _) | ("stop", _) | ("seek", _) => true,
+            ("track", Some(sub)) if matches!(sub.subcommand_name(), Some("next") | Some("prev")) => true,
+            ("volume", Some(sub)) if sub.subcommand_name().is_none() => false,
+            ("ungroup", _) => false,
+            ("group", Some(sub)) if sub.subcommand_name() == Some("leave") => false,
+            (command, _) => bail!("{} can only be run against a single controller", command),
+        };
+
+        let mut results = Vec::new();
+        let cache_file = cache_file(args);
+
+        let mut targets = if args.is_present("all") {
+            discovery::discover(&cache_file, true, false).await?
+        } else {
+            let identifiers = args.values_of("controller").into_iter().flatten().collect::<Vec<_>>();
+            let resolved = futures::future::join_all(identifiers.iter()
+                .map(|id| discovery::find_speaker(&cache_file, id))).await;
+
+            let mut targets = Vec::new();
+
+            for (identifier, speaker) in identifiers.into_iter().zip(resolved) {
+                match speaker {
+                    Ok(speaker) => targets.push(speaker),
+                    Err(e) => results.push(ControllerResult::new(identifier.to_string(), Err(e))),
+                }
+            }
+
+            targets
+        };
+
+        if !targets.is_empty() {
+            let groups = topology::household_groups(&targets).await?;
+
+            // bonded devices aren't rooms of their own and --all would otherwise pick them up
+            targets.retain(|t| groups.iter().flat_map(|g| &g.members).any(|m| m.uuid == t.uuid && !m.invisible));
+
+            if group_level && !args.is_present("no-redirect") {
+                let mut coordinators = Vec::new();
+
+                for target in std::mem::take(&mut targets) {
+                    let coordinator = match groups.iter().find(|g| g.contains(&target.uuid)) {
+                        Some(group) => group.coordinator.clone(),
+                        None => target.uuid.clone(),
+                    };
+
+                    if coordinators.contains(&coordinator) {
+                        continue;
+                    }
+
+                    coordinators.push(coordinator.clone());
+
+                    if coordinator == target.uuid {
+                        targets.push(target);
+                    } else {
+                        match groups.iter().flat_map(|g| &g.members).find(|m| m.uuid == coordinator) {
+                            Some(member) => match util::timeout(timeout, Speaker::from_ip(member.ip)).await {
+                                Ok(speaker) => targets.push(speaker),
+                                Err(e) => results.push(ControllerResult::new(member.name.clone(), Err(e))),
+                            },
+                            None => targets.push(target),
+                        }
+                    }
+                }
+            }
+
+            // deduplicate the same room being given twice, in room order so the confirmation
+            // prompt and results read the same every run
+            targets.sort_by(|a, b| (&a.name, &a.uuid).cmp(&(&b.name, &b.uuid)));
+            targets.dedup_by(|a, b| a.uuid == b.uuid);
+        }
+
+        let mutating = !matches!(args.subcommand(), ("volume", Some(sub)) if sub.value_of("VOLUME").is_none());
+
+        // nothing's sent in a dry run, so there's nothing to be careful about
+        if args.is_present("all") && mutating && !targets.is_empty() && !upnp::dry_run() {
+            let question = format!(
+                "This will affect {} speaker{}: {}. Continue?",
+                targets.len(),
+                if targets.len() == 1 { "" } else { "s" },
+                targets.iter().map(|t| t.name.as_str()).collect::<Vec<_>>().join(", "),
+            );
+
+            if !util::confirm_destructive(&question).await? {
+                bail!("Aborted, no speakers were changed");
+            }
+        }
+
+        let seek = match args.subcommand() {
+            ("seek", Some(sub)) => Some(util::hms_to_duration(sub.value_of("TIMESTAMP").expect("timestamp"))?),
+            _ => None,
+        };
+
+        let volume = match args.subcommand() {
+            ("volume", Some(sub)) => sub.value_of("VOLUME").map(str::parse::<u8>).transpose()?,
+            _ => None,
+        };
+
+        let outcomes = futures::stream::iter(&targets)
+            .map(|speaker| async move {
+                let result = match args.subcommand() {
+                    ("play", _) => util::retry(retries, timeout, || transport::play(speaker.ip)).await.map(|_| None),
+                    ("pause", _) => util::retry(retries, timeout, || transport::pause(speaker.ip)).await.map(|_| None),
+                    ("stop", _) => util::retry(retries, timeout, || transport::stop(speaker.ip)).await.map(|_| None),
+                    ("seek", _) => {
+                        let seek = seek.expect("seek");
+                        util::retry(retries, timeout, || transport::seek(speaker.ip, seek)).await.map(|_| None)
+                    },
+                    ("track", Some(sub)) if sub.subcommand_name() == Some("next") => {
+                        util::timeout(timeout, transport::next(speaker.ip)).await.map(|_| None)
+                    },
+                    ("track", _) => util::timeout(timeout, transport::previous(speaker.ip)).await.map(|_| None),
+                    ("volume", _) => match volume {
+                        Some(volume) => {
+                            let volume = config::cap_volume(&speaker.name, volume);
+                            util::retry(retries, timeout, || volume::set(speaker.ip, volume)).await.map(|_| None)
+                        },
+                        None => util::retry(retries, timeout, || speaker.volume()).await.map(Some),
+                    },
+                    ("ungroup", _) => util::retry(retries, timeout, || topology::leave(speaker)).await.map(|_| None),
+                    _ => util::retry(retries, timeout, || topology::leave(speaker)).await.map(|_| None),
+                };
+
+                ControllerResult::new(speaker.name.clone(), result)
+            })
+            .buffer_unordered(MAX_IN_FLIGHT)
+            .collect::<Vec<_>>()
+            .await;
+
+        results.extend(outcomes);
+        // buffer_unordered hands results back in whatever order they finished
+        results.sort_by(|a, b| a.room.cmp(&b.room));
+
+        Ok(MultiResult(results))
+    }
+}
+impl std::fmt::Display for MultiResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        for (i, result) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            match (&result.error, result.volume) {
+                (Some(error), _) => write!(f, "{}: failed: {}", result.room, error)?,
+                (None, Some(volume)) => write!(f, "{}: {}/100", result.room, volume)?,
+                (None, None) => write!(f, "{}: ok", result.room)?,
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum RoomChange {
+    Appeared { room: String },
+    Disappeared { room: String },
+    Joined { room: String, group: String },
+    Left { room: String, group: String },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct RoomEvent {
+    pub timestamp: String,
+    #[serde(flatten)]
+    pub change: RoomChange,
+}
+impl RoomEvent {
+    pub fn new(change: RoomChange) -> Self {
+        Self {
+            timestamp: chrono::Local::now().to_rfc3339(),
+            change,
+        }
+    }
+}
+impl std::fmt::Display for RoomEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "[{}] ", self.timestamp)?;
+
+        match &self.change {
+            RoomChange::Appeared { room } => write!(f, "{} appeared", room),
+            RoomChange::Disappeared { room } => write!(f, "{} disappeared", room),
+            RoomChange::Joined { room, group } => write!(f, "{} joined {}", room, group),
+            RoomChange::Left { room, group } => write!(f, "{} left {}", room, group),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Renamed {
+    pub old: String,
+    pub new: String,
+}
+impl std::fmt::Display for Renamed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {} {}", self.old, util::glyph(Glyph::Arrow), self.new)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Fadeout {
+    pub room: String,
+    /// Volume before the fade, which the speaker is put back to once paused
+    pub original: u8,
+    pub target: u8,
+}
+impl std::fmt::Display for Fadeout {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} faded out from {} to {} and paused, volume reset to {}", self.room, self.original, self.target, self.original)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct AlarmList(Vec<alarms::Alarm>);
+impl AlarmList {
+    /// Fill in the name of each alarm's room from the speaker cache.
+    pub async fn new(cache_file: &std::path::Path, mut alarms: Vec<alarms::Alarm>) -> Fallible<AlarmList> {
+        let speakers = discovery::cached_speakers(cache_file, true, false).await?;
+
+        for alarm in &mut alarms {
+            alarm.room = speakers.iter().find(|s| s.uuid == alarm.room_uuid).map(|s| s.name.clone());
+        }
+
+        Ok(AlarmList(alarms))
+    }
+}
+impl std::fmt::Display for AlarmList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No alarms set");
+        }
+
+        let room = |alarm: &alarms::Alarm| alarm.room.clone().unwrap_or_else(|| alarm.room_uuid.clone());
+        let width = self.0.iter().map(|a| room(a).chars().count()).max().unwrap_or(0).max(4);
+
+        let row = |f: &mut std::fmt::Formatter, [id, time, length, recurrence, room, volume, enabled]: [&str; 7]| {
+            write!(f, "{:>4}  {:<8}  {:<8}  {:<14}  {:<width$}  {:>6}  {}", id, time, length, recurrence, room, volume, enabled, width = width)
+        };
+
+        row(f, ["ID", "TIME", "LENGTH", "RECURRENCE", "ROOM", "VOLUME", "ENABLED"])?;
+
+        for alarm in &self.0 {
+            writeln!(f)?;
+            row(f, [
+                &alarm.id.to_string(),
+                &alarm.start_time,
+                &util::duration_to_hms(alarm.duration),
+                &alarm.recurrence,
+                &room(alarm),
+                &alarm.volume.to_string(),
+                if alarm.enabled { "yes" } else { "no" },
+            ])?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AlarmCreated {
+    pub id: u32,
+}
+impl std::fmt::Display for AlarmCreated {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.id)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct AlarmDeleted {
+    pub deleted: u32,
+    #[serde(skip)]
+    pub remaining: AlarmList,
+}
+impl std::fmt::Display for AlarmDeleted {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Deleted alarm {}\n\n{}", self.deleted, self.remaining)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Clock {
+    pub room: String,
+    pub local_time: String,
+    pub utc_time: String,
+    /// Time zone rule configured on the speaker
+    pub time_zone: String,
+    pub auto_adjust_dst: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub time_server: Option<String>,
+    /// Local time on this machine, to compare against
+    pub host_time: String,
+}
+impl Clock {
+    pub async fn new(speaker: &Speaker) -> Fallible<Clock> {
+        let now = alarms::time_now(speaker.ip).await?;
+
+        Ok(Clock {
+            room: speaker.name.clone(),
+            local_time: now.local,
+            utc_time: now.utc,
+            time_zone: now.time_zone,
+            auto_adjust_dst: now.auto_adjust_dst,
+            time_server: now.time_server,
+            host_time: chrono::Local::now().format("%Y-%m-%d %H:%M:%S").to_string(),
+        })
+    }
+}
+impl std::fmt::Display for Clock {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        writeln!(f, "{}", util::heading(Glyph::Clock, &self.room))?;
+
+        writeln!(f, "Local time: {}", self.local_time)?;
+        writeln!(f, "UTC time: {}", self.utc_time)?;
+        writeln!(f, "Time zone: {}{}", self.time_zone, if self.auto_adjust_dst { " (adjusts for DST)" } else { "" })?;
+
+        if let Some(server) = &self.time_server {
+            writeln!(f, "Time server: {}", server)?;
+        }
+
+        write!(f, "This machine: {}", self.host_time)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ManualSpeakers(Vec<discovery::CachedSpeaker>);
+impl std::fmt::Display for ManualSpeakers {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No speakers have been added by hand");
+        }
+
+        for (i, speaker) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{} ({}, {})", speaker.name, speaker.ip, speaker.uuid)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct DefaultController {
+    pub controller: Option<String>,
+    /// Where the default controller came from, the environment or the config file
+    pub source: Option<String>,
+    pub name: Option<String>,
+    pub reachable: bool,
+}
+impl DefaultController {
+    pub async fn new(cache_file: &std::path::Path) -> Self {
+        let (source, controller) = match default_controller() {
+            Some((source, controller)) => (Some(source.to_string()), Some(controller)),
+            None => (None, None),
+        };
+
+        let speaker = match &controller {
+            Some(controller) => discovery::find_speaker(cache_file, controller).await.ok(),
+            None => None,
+        };
+
+        Self {
+            controller,
+            source,
+            name: speaker.as_ref().map(|s| s.name.clone()),
+            reachable: speaker.is_some(),
+        }
+    }
+}
+impl std::fmt::Display for DefaultController {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let (controller, source) = match (&self.controller, &self.source) {
+            (Some(controller), Some(source)) => (controller, source),
+            _ => return write!(f, "No default controller, commands need -c"),
+        };
+
+        match &self.name {
+            Some(name) if name != controller => write!(f, "{} ({}) from {}", name, controller, source)?,
+            _ => write!(f, "{} from {}", controller, source)?,
+        }
+
+        write!(f, ", {}", if self.reachable { "reachable" } else { "currently unreachable" })
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct FavoriteList(Vec<favorites::Favorite>);
+impl std::fmt::Display for FavoriteList {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        if self.0.is_empty() {
+            return write!(f, "No favorites saved");
+        }
+
+        for (i, favorite) in self.0.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            write!(f, "{}{}", util::glyph(Glyph::Favorite), favorite.title)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn topology_buckets_members_under_their_coordinator() {
+        let state = r#"<ZoneGroupState><ZoneGroups>
+            <ZoneGroup Coordinator="RINCON_B" ID="RINCON_B:1">
+                <ZoneGroupMember UUID="RINCON_A" Location="http://10.0.0.1:1400/xml/device_description.xml" ZoneName="Kitchen"/>
+                <ZoneGroupMember UUID="RINCON_B" Location="http://10.0.0.2:1400/xml/device_description.xml" ZoneName="Living Room"/>
+                <ZoneGroupMember UUID="RINCON_C" Location="http://10.0.0.3:1400/xml/device_description.xml" ZoneName="Dining Room"/>
+            </ZoneGroup>
+            <ZoneGroup Coordinator="RINCON_D" ID="RINCON_D:2">
+                <ZoneGroupMember UUID="RINCON_D" Location="http://10.0.0.4:1400/xml/device_description.xml" ZoneName="Bedroom"/>
+                <ZoneGroupMember UUID="RINCON_E" Location="http://10.0.0.5:1400/xml/device_description.xml" ZoneName="Bedroom" Invisible="1"/>
+            </ZoneGroup>
+        </ZoneGroups></ZoneGroupState>"#;
+
+        let Topology(groups) = Topology::from_groups(&topology::parse_zone_groups(state).unwrap()).unwrap();
+
+        let buckets = groups.iter()
+            .map(|g| (g.coordinator.uuid.as_str(), g.members.iter().map(|m| m.name.as_str()).collect::<Vec<_>>()))
+            .collect::<Vec<_>>();
+
+        assert_eq!(buckets, vec![
+            ("RINCON_D", vec!["Bedroom"]),
+            ("RINCON_B", vec!["Dining Room", "Kitchen", "Living Room"]),
+        ]);
+        assert_eq!(groups[0].members[0].bonded.len(), 1);
+    }
+
+    #[test]
+    fn volume_json_parses_back() {
+        let volume = Volume { volume: 35, muted: true };
+
+        // what print_struct! writes for `--json volume`
+        let json = util::to_json(&util::Envelope::data(&volume)).unwrap();
+        let parsed: util::Envelope<Volume> = serde_json::from_str(&json).unwrap();
+
+        let parsed = parsed.data.unwrap();
+        assert_eq!((parsed.volume, parsed.muted), (35, true));
+    }
+
+    fn round_trip<T: serde::Serialize + serde::de::DeserializeOwned>(data: &T) -> util::Envelope<T> {
+        let json = util::to_json(&util::Envelope::data(data)).unwrap();
+        let envelope: util::Envelope<T> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(envelope.schema, util::SCHEMA_VERSION);
+        assert!(envelope.error.is_none());
+
+        envelope
+    }
+
+    fn track_list_item(position: u64, title: &str) -> TrackListItem {
+        TrackListItem {
+            position,
+            title: title.to_string(),
+            artist: "Artist".to_string(),
+            album: "Album".to_string(),
+            duration: Duration::from_secs(200),
+            uri: format!("x-file-cifs://nas/{}.flac", position),
+            album_art: None,
+        }
+    }
+
+    #[test]
+    fn envelope_round_trips_track() {
+        let track = Track {
+            title: "Title".to_string(),
+            artist: "Artist".to_string(),
+            album: Some("Album".to_string()),
+            running_time: Duration::from_secs(65),
+            duration: Duration::from_secs(3725),
+        };
+
+        let parsed = round_trip(&track).data.unwrap();
+
+        assert_eq!((parsed.title.as_str(), parsed.artist.as_str(), parsed.album.as_deref()), ("Title", "Artist", Some("Album")));
+        assert_eq!((parsed.running_time, parsed.duration), (track.running_time, track.duration));
+    }
+
+    #[test]
+    fn envelope_round_trips_track_list() {
+        let list = TrackList {
+            filters: TrackFilter::default(),
+            tracks: vec![track_list_item(1, "First"), track_list_item(2, "Second")],
+            inserted: None,
+            show_uris: false,
+            table: None,
+        };
+
+        let parsed = round_trip(&list).data.unwrap();
+
+        assert!(parsed.filters.is_empty());
+        assert_eq!(parsed.tracks.iter().map(|t| (t.position, t.title.as_str())).collect::<Vec<_>>(), vec![(1, "First"), (2, "Second")]);
+        assert_eq!(parsed.tracks[1].duration, Duration::from_secs(200));
+
+        let filtered = TrackList {
+            filters: TrackFilter { artist: Some("artist".to_string()), ..TrackFilter::default() },
+            ..list
+        };
+
+        let parsed = round_trip(&filtered).data.unwrap();
+
+        assert_eq!(parsed.filters.artist.as_deref(), Some("artist"));
+        assert_eq!(parsed.tracks.len(), 2);
+    }
+
+    #[test]
+    fn envelope_round_trips_errors() {
+        let json = util::to_json(&util::Envelope::error("not_found", "Couldn't find a speaker named 'attic'".to_string())).unwrap();
+        let envelope: util::Envelope<()> = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(envelope.schema, util::SCHEMA_VERSION);
+        assert!(envelope.data.is_none());
+
+        let error = envelope.error.unwrap();
+        assert_eq!((error.kind.as_str(), error.message.as_str()), ("not_found", "Couldn't find a speaker named 'attic'"));
+    }
+
+    #[test]
+    fn error_kind_goes_by_type() {
+        use failure::ResultExt;
+
+        assert_eq!(error_kind(&not_found!("Couldn't find a speaker named 'attic'")), "not_found");
+        assert_eq!(error_kind(&invalid_argument!("Expected a volume between 0 and 100, got 200")), "invalid_argument");
+        assert_eq!(error_kind(&format_err!("Couldn't find a route to the speaker at 10.0.0.1")), "error");
+
+        let wrapped = Err::<(), _>(not_found!("Couldn't find a speaker named 'attic'")).context("controller from the config file");
+        assert_eq!(error_kind(&wrapped.unwrap_err().into()), "not_found");
     }
 }
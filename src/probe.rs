@@ -0,0 +1,121 @@
+use failure::Fallible;
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, CONTENT_TYPE};
+use reqwest::{StatusCode, Url};
+
+use crate::didl;
+
+/// Content types speakers can play, either as a file or as a stream.
+const SUPPORTED: &[&str] = &[
+    "audio/mpeg", "audio/mp3", "audio/mpeg3", "audio/x-mpeg",
+    "audio/aac", "audio/aacp", "audio/mp4", "audio/x-m4a",
+    "audio/flac", "audio/x-flac",
+    "audio/ogg", "application/ogg",
+    "audio/wav", "audio/x-wav", "audio/wave",
+    "audio/aiff", "audio/x-aiff",
+    "audio/x-ms-wma",
+];
+
+/// Content types of playlists, which speakers won't follow when given them directly.
+const PLAYLISTS: &[&str] = &[
+    "audio/x-mpegurl", "audio/mpegurl", "application/vnd.apple.mpegurl", "application/x-mpegurl",
+    "audio/x-scpls", "application/pls+xml",
+];
+
+/// What's at a URL, and how the speaker needs to be told to play it.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Probed {
+    /// Where the URL ended up after following redirects
+    pub url: String,
+    /// The URI to hand the speaker
+    pub uri: String,
+    pub content_type: String,
+    pub stream: bool,
+    #[serde(skip)]
+    pub metadata: String,
+}
+impl std::fmt::Display for Probed {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Playing {} as a {} ({})", self.url, if self.stream { "stream" } else { "file" }, self.content_type)
+    }
+}
+
+/// Find out what's at `url` and build the URI and metadata a speaker will actually play it
+/// with. Redirects are followed up front since speakers give up on some of them, and live
+/// streams get the `x-rincon-mp3radio` scheme so they're treated as radio rather than a file
+/// of unknown length.
+pub async fn probe(url: &str, title: Option<&str>) -> Fallible<Probed> {
+    let url = Url::parse(url).map_err(|e| invalid_argument!("Expected an http(s) URL, got {}: {}", url, e))?;
+
+    if !matches!(url.scheme(), "http" | "https") {
+        return Err(invalid_argument!("Expected an http(s) URL, got {}", url));
+    }
+
+    let client = reqwest::Client::new();
+
+    let mut response = client.head(url.clone()).send().await?;
+
+    // plenty of stream servers don't implement HEAD, only the headers of a GET are read
+    if matches!(response.status(), StatusCode::METHOD_NOT_ALLOWED | StatusCode::NOT_IMPLEMENTED | StatusCode::FORBIDDEN) {
+        response = client.get(url.clone()).send().await?;
+    }
+
+    if !response.status().is_success() {
+        bail!("{} responded with {}", response.url(), response.status());
+    }
+
+    let headers = response.headers();
+    let final_url = response.url().clone();
+
+    let content_type = headers.get(CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.split(';').next())
+        .map(|v| v.trim().to_lowercase())
+        .unwrap_or_default();
+
+    if PLAYLISTS.contains(&content_type.as_str()) {
+        bail!("{} is a playlist ({}), pass the URL of one of the streams listed in it instead", final_url, content_type);
+    }
+
+    if !SUPPORTED.contains(&content_type.as_str()) {
+        bail!(
+            "{} has a content type of {}, which speakers can't play. Supported types are: {}",
+            final_url,
+            if content_type.is_empty() { "nothing" } else { &content_type },
+            SUPPORTED.join(", "),
+        );
+    }
+
+    let stream = is_stream(headers);
+    let title = title.map(String::from)
+        .or_else(|| header(headers, "icy-name"))
+        .or_else(|| final_url.path_segments().and_then(|mut s| s.next_back()).filter(|s| !s.is_empty()).map(String::from))
+        .unwrap_or_else(|| final_url.to_string());
+
+    let uri = match final_url.as_str().strip_prefix("http://") {
+        Some(rest) if stream => format!("x-rincon-mp3radio://{}", rest),
+        _ => final_url.to_string(),
+    };
+
+    let metadata = didl::Item {
+        id: "R:0/0/0",
+        parent_id: "R:0/0",
+        title: &title,
+        class: if stream { "object.item.audioItem.audioBroadcast" } else { "object.item.audioItem.musicTrack" },
+        ..didl::Item::default()
+    }.to_didl();
+
+    Ok(Probed { url: final_url.to_string(), uri, content_type, stream, metadata })
+}
+
+/// Live streams are either Shoutcast/Icecast, which always send `icy-` headers, or have no
+/// length since they never end.
+fn is_stream(headers: &HeaderMap) -> bool {
+    headers.keys().any(|k| k.as_str().starts_with("icy-")) || !headers.contains_key(CONTENT_LENGTH)
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers.get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.trim().to_string())
+        .filter(|v| !v.is_empty())
+}
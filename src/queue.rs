@@ -0,0 +1,212 @@
+use std::time::Duration;
+
+use sonos::Speaker;
+use failure::Fallible;
+
+use crate::transport;
+use crate::upnp::{self, Service};
+use crate::util;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Enqueued {
+    pub position: u64,
+    pub added: u64,
+    pub queue_length: u64,
+}
+impl std::fmt::Display for Enqueued {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "Added {} item(s) to the queue starting at position {}", self.added, self.position)
+    }
+}
+
+#[derive(Debug)]
+pub struct QueueItem {
+    pub position: u64,
+    pub uri: String,
+    pub title: String,
+    pub artist: String,
+    pub album: String,
+    pub album_art: Option<String>,
+    pub duration: Duration,
+    pub metadata: String,
+}
+
+/// Fetch every item in the speaker's queue, including the URIs and album art the `sonos` crate
+/// doesn't expose.
+pub async fn list(speaker: &Speaker) -> Fallible<Vec<QueueItem>> {
+    // the speaker returns at most 100 items per browse request
+    const PAGE_SIZE: usize = 100;
+
+    let mut items = Vec::new();
+
+    loop {
+        let response = upnp::call(speaker.ip, Service::ContentDirectory, "Browse", &[
+            ("ObjectID", "Q:0"),
+            ("BrowseFlag", "BrowseDirectChildren"),
+            ("Filter", "*"),
+            ("StartingIndex", &items.len().to_string()),
+            ("RequestedCount", &PAGE_SIZE.to_string()),
+            ("SortCriteria", ""),
+        ]).await?;
+
+        let total = upnp::child_text(&response, "TotalMatches")
+            .and_then(|v| v.parse::<usize>().ok())
+            .unwrap_or(0);
+        let didl = upnp::child_text(&response, "Result").unwrap_or_default();
+
+        let page = upnp::didl_items(&didl)?;
+        let returned = page.len();
+
+        for item in page {
+            let res = item.get_child("res");
+
+            items.push(QueueItem {
+                position: items.len() as u64 + 1,
+                uri: res.and_then(|r| r.get_text()).map(|t| t.into_owned()).unwrap_or_default(),
+                title: upnp::child_text(&item, "title").unwrap_or_default(),
+                artist: upnp::child_text(&item, "creator").unwrap_or_default(),
+                album: upnp::child_text(&item, "album").unwrap_or_default(),
+                album_art: upnp::child_text(&item, "albumArtURI")
+                    .map(|path| upnp::resolve_url(speaker.ip, &path)),
+                duration: res.and_then(|r| r.attributes.get("duration"))
+                    .and_then(|d| util::hms_to_duration(d.split('.').next().unwrap_or_default()).ok())
+                    .unwrap_or_default(),
+                metadata: upnp::didl_document(&item)?,
+            });
+        }
+
+        if returned == 0 || items.len() >= total {
+            break Ok(items);
+        }
+    }
+}
+
+/// Number of items in the speaker's queue.
+pub async fn length(speaker: &Speaker) -> Fallible<u64> {
+    let response = upnp::call(speaker.ip, Service::ContentDirectory, "Browse", &[
+        ("ObjectID", "Q:0"),
+        ("BrowseFlag", "BrowseDirectChildren"),
+        ("Filter", ""),
+        ("StartingIndex", "0"),
+        ("RequestedCount", "1"),
+        ("SortCriteria", ""),
+    ]).await?;
+
+    upnp::child_text(&response, "TotalMatches")
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| format_err!("Browse response is missing TotalMatches"))
+}
+
+/// Add a URI (or every track of a container URI) to the speaker's queue, with optional DIDL-Lite
+/// metadata. A `position` of 0 appends to the end of the queue.
+pub async fn add(speaker: &Speaker, uri: &str, metadata: &str, position: u64) -> Fallible<Enqueued> {
+    enqueue(speaker, uri, metadata, position, false).await
+}
+
+/// Add a URI straight after the track that's currently playing, so it plays next without
+/// disturbing the rest of the queue. Appends if the speaker isn't playing from its queue or the
+/// current track is the last one.
+pub async fn play_next(speaker: &Speaker, uri: &str, metadata: &str) -> Fallible<Enqueued> {
+    let (current, length) = futures::try_join!(position(speaker), length(speaker))?;
+
+    // the speaker rejects positions past the end of the queue rather than appending, so playing
+    // the last track (or nothing from an empty queue) has to append explicitly
+    let position = match current {
+        Some(current) if current.track < length => current.track + 1,
+        _ => 0,
+    };
+
+    enqueue(speaker, uri, metadata, position, true).await
+}
+
+async fn enqueue(speaker: &Speaker, uri: &str, metadata: &str, position: u64, as_next: bool) -> Fallible<Enqueued> {
+    let response = upnp::call(speaker.ip, Service::AVTransport, "AddURIToQueue", &[
+        ("InstanceID", "0"),
+        ("EnqueuedURI", uri),
+        ("EnqueuedURIMetaData", metadata),
+        ("DesiredFirstTrackNumberEnqueued", &position.to_string()),
+        ("EnqueueAsNext", if as_next { "1" } else { "0" }),
+    ]).await?;
+
+    let field = |name| upnp::child_text(&response, name)
+        .and_then(|v| v.parse::<u64>().ok())
+        .ok_or_else(|| format_err!("AddURIToQueue response is missing {}", name));
+
+    Ok(Enqueued {
+        position: field("FirstTrackNumberEnqueued")?,
+        added: field("NumTracksAdded")?,
+        queue_length: field("NewQueueLength")?,
+    })
+}
+
+/// Remove every item from the speaker's queue.
+pub async fn clear(speaker: &Speaker) -> Fallible<()> {
+    upnp::call(speaker.ip, Service::AVTransport, "RemoveAllTracksFromQueue", &[("InstanceID", "0")]).await?;
+
+    Ok(())
+}
+
+/// Replace everything in the speaker's queue with the given `(uri, metadata)` pairs.
+pub async fn replace<'a>(speaker: &Speaker, items: impl IntoIterator<Item = (&'a str, &'a str)>) -> Fallible<()> {
+    clear(speaker).await?;
+
+    for (uri, metadata) in items {
+        add(speaker, uri, metadata, 0).await?;
+    }
+
+    Ok(())
+}
+
+/// Insert a URI at the given queue position. Positions of 0 or past the end of the queue append,
+/// and inserting before the current track keeps the current track playing.
+pub async fn insert(speaker: &Speaker, uri: &str, position: u64) -> Fallible<Enqueued> {
+    if position == 0 || position > length(speaker).await? {
+        return add(speaker, uri, "", 0).await;
+    }
+
+    let current = self::position(speaker).await?;
+    let enqueued = add(speaker, uri, "", position).await?;
+
+    if let Some(current) = current.filter(|c| c.track >= position) {
+        // the playing track moved down the queue with everything else, make sure the speaker
+        // is still pointing at it
+        let expected = current.track + enqueued.added;
+
+        if self::position(speaker).await?.map(|p| p.track) != Some(expected) {
+            transport::skip_to(speaker.ip, expected).await?;
+            transport::seek(speaker.ip, current.elapsed).await?;
+        }
+    }
+
+    Ok(enqueued)
+}
+
+pub struct Position {
+    pub track: u64,
+    pub elapsed: Duration,
+}
+
+/// The speaker's current position in its queue, `None` if it isn't playing from the queue.
+pub async fn position(speaker: &Speaker) -> Fallible<Option<Position>> {
+    let media = upnp::call(speaker.ip, Service::AVTransport, "GetMediaInfo", &[("InstanceID", "0")]).await?;
+
+    if !upnp::child_text(&media, "CurrentURI").unwrap_or_default().starts_with("x-rincon-queue:") {
+        return Ok(None);
+    }
+
+    let info = upnp::call(speaker.ip, Service::AVTransport, "GetPositionInfo", &[("InstanceID", "0")]).await?;
+
+    Ok(Some(Position {
+        track: upnp::child_text(&info, "Track")
+            .and_then(|v| v.parse().ok())
+            .ok_or_else(|| format_err!("GetPositionInfo response is missing Track"))?,
+        elapsed: upnp::child_text(&info, "RelTime")
+            .and_then(|v| util::hms_to_duration(&v).ok())
+            .unwrap_or_default(),
+    }))
+}
+
+/// Switch the speaker's transport over to its own queue.
+pub async fn use_queue(speaker: &Speaker) -> Fallible<()> {
+    transport::set_uri(speaker.ip, &format!("x-rincon-queue:{}#0", speaker.uuid), "").await
+}
@@ -0,0 +1,123 @@
+use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::path::Path;
+use std::time::Duration;
+
+use sonos::Speaker;
+use failure::Fallible;
+use futures::future::Either;
+use hyper::{Body, Response};
+use hyper::service::{make_service_fn, service_fn};
+
+use crate::transport;
+
+/// How often to check whether the speaker is still playing the file.
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The address of ours the speaker would see our traffic come from, which is the one it can reach
+/// us on.
+fn local_ip_for(speaker: IpAddr) -> Fallible<IpAddr> {
+    let bind = match speaker {
+        IpAddr::V4(_) => "0.0.0.0:0",
+        IpAddr::V6(_) => "[::]:0",
+    };
+
+    // connecting a UDP socket doesn't send anything, it only picks the route
+    let socket = UdpSocket::bind(bind)?;
+    socket.connect(SocketAddr::new(speaker, 1400))
+        .map_err(|e| format_err!("Couldn't find a route to the speaker at {}: {}", speaker, e))?;
+
+    Ok(socket.local_addr()?.ip())
+}
+
+fn content_type(path: &Path) -> &'static str {
+    match path.extension().and_then(|e| e.to_str()).map(str::to_lowercase).as_deref() {
+        Some("mp3") => "audio/mpeg",
+        Some("flac") => "audio/flac",
+        Some("m4a") | Some("aac") | Some("mp4") => "audio/mp4",
+        Some("ogg") | Some("oga") => "audio/ogg",
+        Some("wav") => "audio/wav",
+        Some("aif") | Some("aiff") => "audio/aiff",
+        Some("wma") => "audio/x-ms-wma",
+        _ => "application/octet-stream",
+    }
+}
+
+/// Serve a single local file over HTTP and play it on the speaker, returning once the speaker
+/// has finished playing it (or moved on to something else). `serving` is called with the file's
+/// URL once it's being served. Returns `false` if stopped early with Ctrl-C.
+pub async fn play_file(speaker: &Speaker, path: &Path, serving: impl FnOnce(&str)) -> Fallible<bool> {
+    let contents = tokio::fs::read(path).await
+        .map_err(|e| format_err!("Couldn't read {}: {}", path.display(), e))?;
+    let name = path.file_name()
+        .and_then(|n| n.to_str())
+        .ok_or_else(|| invalid_argument!("Expected a path to a file, got {}", path.display()))?
+        .to_string();
+    let content_type = content_type(path);
+
+    let listener = std::net::TcpListener::bind(SocketAddr::new(local_ip_for(speaker.ip)?, 0))?;
+    let address = listener.local_addr()?;
+
+    // the file's the only thing being served, so whatever path the speaker asks for gets it
+    let contents = hyper::body::Bytes::from(contents);
+    let server = hyper::Server::from_tcp(listener)?
+        .serve(make_service_fn(move |_| {
+            let contents = contents.clone();
+
+            async move {
+                Ok::<_, hyper::Error>(service_fn(move |_| {
+                    let response = Response::builder()
+                        .header("Content-Type", content_type)
+                        .header("Content-Length", contents.len())
+                        .body(Body::from(contents.clone()));
+
+                    async move { response }
+                }))
+            }
+        }));
+
+    let mut uri = reqwest::Url::parse(&format!("http://{}/", address))?;
+    uri.path_segments_mut()
+        .map_err(|_| format_err!("Couldn't build a URL for {}", address))?
+        .pop_if_empty()
+        .push(&name);
+    let uri = uri.to_string();
+
+    serving(&uri);
+
+    let playback = async {
+        // set directly rather than through the queue, so the transport URI tells us whether the
+        // speaker's still on the file
+        transport::set_uri(speaker.ip, &uri, "").await?;
+        speaker.play().await?;
+
+        // the speaker goes through TRANSITIONING while it buffers, it's only finished once it's
+        // stopped or switched to playing something else
+        loop {
+            tokio::time::delay_for(POLL_INTERVAL).await;
+
+            let (state, media) = futures::try_join!(transport::state(speaker.ip), transport::media_info(speaker.ip))?;
+
+            if state == "STOPPED" || media.uri != uri {
+                break Ok::<(), failure::Error>(());
+            }
+        }
+    };
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    futures::pin_mut!(playback, ctrl_c);
+
+    let playback = futures::future::select(playback, ctrl_c);
+
+    match futures::future::select(playback, server).await {
+        Either::Left((Either::Left((result, _)), _)) => result.map(|_| true),
+        Either::Left((Either::Right(_), _)) => {
+            // nothing will be serving the file anymore, so stop the speaker trying to buffer it
+            speaker.stop().await?;
+            Ok(false)
+        },
+        Either::Right((result, _)) => {
+            result?;
+            bail!("The file server stopped unexpectedly")
+        },
+    }
+}
@@ -0,0 +1,275 @@
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use sonos::Speaker;
+use failure::Fallible;
+use futures::future::join_all;
+
+use crate::{discovery, queue, topology, transport, volume};
+
+fn snapshot_dir() -> PathBuf {
+    crate::util::cache_dir().join("snapshots")
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Snapshot {
+    pub name: String,
+    pub created: String,
+    /// Every room in the household when the snapshot was taken, keyed by UUID
+    pub speakers: BTreeMap<String, SpeakerState>,
+    pub groups: Vec<GroupState>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SpeakerState {
+    pub name: String,
+    pub ip: IpAddr,
+    pub volume: u8,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GroupState {
+    pub coordinator: String,
+    /// UUIDs of the rooms in the group other than the coordinator
+    pub members: Vec<String>,
+    pub uri: String,
+    pub metadata: String,
+    pub play_mode: String,
+    pub playing: bool,
+    /// Contents of the coordinator's queue, only captured when it's playing from the queue
+    #[serde(default)]
+    pub queue: Vec<QueuedUri>,
+    pub track: Option<u64>,
+    pub elapsed: Option<Duration>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct QueuedUri {
+    pub uri: String,
+    pub metadata: String,
+}
+
+pub struct Restored {
+    pub groups: usize,
+    /// Rooms from the snapshot that couldn't be found anymore
+    pub missing: Vec<String>,
+}
+
+/// Capture the grouping, playback and volume of every room in the household.
+pub async fn capture(name: &str, devices: &[Speaker]) -> Fallible<Snapshot> {
+    let mut snapshot = Snapshot {
+        name: name.to_string(),
+        created: chrono::Local::now().to_rfc3339(),
+        speakers: BTreeMap::new(),
+        groups: Vec::new(),
+    };
+
+    for group in topology::household_groups(devices).await? {
+        let members = group.members.iter().filter(|m| !m.invisible).collect::<Vec<_>>();
+
+        // a room that's unplugged shouldn't stop the rest of the household being captured
+        let rooms = join_all(members.iter().map(|m| Speaker::from_ip(m.ip))).await
+            .into_iter()
+            .zip(&members)
+            .filter_map(|(room, member)| match room {
+                Ok(room) => Some(room),
+                Err(e) => {
+                    warn!("Leaving {} out of the snapshot, it couldn't be reached: {}", member.name, e);
+                    None
+                },
+            })
+            .collect::<Vec<_>>();
+
+        for room in &rooms {
+            snapshot.speakers.insert(room.uuid.clone(), SpeakerState {
+                name: room.name.clone(),
+                ip: room.ip,
+                volume: room.volume().await?,
+            });
+        }
+
+        let coordinator = match rooms.iter().find(|r| r.uuid == group.coordinator) {
+            Some(coordinator) => coordinator,
+            None => continue,
+        };
+
+        let (media, state, play_mode) = futures::try_join!(
+            transport::media_info(coordinator.ip),
+            transport::state(coordinator.ip),
+            transport::play_mode(coordinator.ip),
+        )?;
+
+        let mut state = GroupState {
+            coordinator: coordinator.uuid.clone(),
+            members: rooms.iter().filter(|r| r.uuid != coordinator.uuid).map(|r| r.uuid.clone()).collect(),
+            uri: media.uri,
+            metadata: media.metadata,
+            play_mode,
+            playing: state == "PLAYING",
+            queue: Vec::new(),
+            track: None,
+            elapsed: None,
+        };
+
+        if let Some(position) = queue::position(coordinator).await? {
+            state.queue = queue::list(coordinator).await?
+                .into_iter()
+                .map(|item| QueuedUri { uri: item.uri, metadata: item.metadata })
+                .collect();
+            state.track = Some(position.track);
+            state.elapsed = Some(position.elapsed);
+        }
+
+        snapshot.groups.push(state);
+    }
+
+    Ok(snapshot)
+}
+
+/// Put the household back the way it was when the snapshot was taken: regroup the rooms, reload
+/// what each group was playing and reset volumes. Rooms that have since disappeared are skipped,
+/// if a group's coordinator is gone one of its other rooms takes over.
+pub async fn restore(cache_file: &Path, snapshot: &Snapshot) -> Fallible<Restored> {
+    let mut speakers = join_all(snapshot.speakers.values().map(|s| Speaker::from_ip(s.ip))).await
+        .into_iter()
+        .filter_map(Result::ok)
+        .filter(|s| snapshot.speakers.contains_key(&s.uuid))
+        .collect::<Vec<_>>();
+
+    if speakers.len() < snapshot.speakers.len() {
+        // addresses might have changed since the snapshot was taken, look again before giving up
+        if let Ok(devices) = discovery::discover(cache_file, false, true).await {
+            for device in devices {
+                if snapshot.speakers.contains_key(&device.uuid) && !speakers.iter().any(|s| s.uuid == device.uuid) {
+                    speakers.push(device);
+                }
+            }
+        }
+    }
+
+    let missing = snapshot.speakers.iter()
+        .filter(|(uuid, _)| !speakers.iter().any(|s| &s.uuid == *uuid))
+        .map(|(_, s)| s.name.clone())
+        .collect();
+
+    if speakers.is_empty() {
+        bail!("None of the rooms in the {} snapshot could be found", snapshot.name);
+    }
+
+    let find = |uuid: &str| speakers.iter().find(|s| s.uuid == uuid);
+
+    let current = topology::household_groups(&speakers).await?;
+    let current_coordinator = |uuid: &str| current.iter()
+        .find(|g| g.contains(uuid))
+        .map(|g| g.coordinator.as_str());
+
+    let groups = snapshot.groups.iter()
+        .filter_map(|group| {
+            std::iter::once(&group.coordinator)
+                .chain(&group.members)
+                .find_map(|uuid| find(uuid))
+                .map(|coordinator| (group, coordinator))
+        })
+        .collect::<Vec<_>>();
+
+    // every coordinator has to be standalone before the other rooms can join it
+    for (_, coordinator) in &groups {
+        if current_coordinator(&coordinator.uuid) != Some(coordinator.uuid.as_str()) {
+            topology::leave(coordinator).await?;
+        }
+    }
+
+    for (group, coordinator) in &groups {
+        for member in group.members.iter().filter_map(|uuid| find(uuid)) {
+            if member.uuid != coordinator.uuid && current_coordinator(&member.uuid) != Some(coordinator.uuid.as_str()) {
+                topology::join(member, &coordinator.uuid).await?;
+            }
+        }
+    }
+
+    for (group, coordinator) in &groups {
+        if group.uri.starts_with("x-rincon-queue:") {
+            queue::replace(coordinator, group.queue.iter().map(|i| (i.uri.as_str(), i.metadata.as_str()))).await?;
+            queue::use_queue(coordinator).await?;
+
+            if let Some(track) = group.track {
+                transport::skip_to(coordinator.ip, track).await?;
+                transport::seek(coordinator.ip, group.elapsed.unwrap_or_default()).await?;
+            }
+        } else if !group.uri.is_empty() {
+            transport::set_uri(coordinator.ip, &group.uri, &group.metadata).await?;
+        }
+
+        if !group.uri.is_empty() {
+            transport::set_play_mode(coordinator.ip, &group.play_mode).await?;
+        }
+
+        if group.playing {
+            transport::play(coordinator.ip).await?;
+        } else if transport::state(coordinator.ip).await? == "PLAYING" {
+            transport::stop(coordinator.ip).await?;
+        }
+    }
+
+    for speaker in &speakers {
+        volume::set(speaker.ip, snapshot.speakers[&speaker.uuid].volume).await?;
+    }
+
+    Ok(Restored { groups: groups.len(), missing })
+}
+
+fn path(name: &str) -> Fallible<PathBuf> {
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') {
+        bail!("Snapshot names can only contain letters, numbers, - and _, got {}", name);
+    }
+
+    Ok(snapshot_dir().join(format!("{}.json", name)))
+}
+
+pub async fn save(snapshot: &Snapshot) -> Fallible<()> {
+    let path = path(&snapshot.name)?;
+
+    tokio::fs::create_dir_all(snapshot_dir()).await?;
+    tokio::fs::write(path, serde_json::to_vec_pretty(snapshot)?).await?;
+
+    Ok(())
+}
+
+pub async fn load(name: &str) -> Fallible<Snapshot> {
+    let contents = tokio::fs::read(path(name)?).await
+        .map_err(|_| not_found!("Couldn't find a snapshot named {}", name))?;
+
+    Ok(serde_json::from_slice(&contents)?)
+}
+
+pub async fn delete(name: &str) -> Fallible<()> {
+    tokio::fs::remove_file(path(name)?).await
+        .map_err(|_| not_found!("Couldn't find a snapshot named {}", name))?;
+
+    Ok(())
+}
+
+/// Every saved snapshot, files that can't be parsed are skipped.
+pub async fn list() -> Fallible<Vec<Snapshot>> {
+    let mut dir = match tokio::fs::read_dir(snapshot_dir()).await {
+        Ok(dir) => dir,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e.into()),
+    };
+
+    let mut snapshots = Vec::new();
+
+    while let Some(entry) = dir.next_entry().await? {
+        if entry.path().extension().is_some_and(|ext| ext == "json") {
+            if let Ok(snapshot) = serde_json::from_slice(&tokio::fs::read(entry.path()).await?) {
+                snapshots.push(snapshot);
+            }
+        }
+    }
+
+    snapshots.sort_by(|a: &Snapshot, b| a.name.cmp(&b.name));
+
+    Ok(snapshots)
+}
@@ -0,0 +1,105 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use failure::Fallible;
+use xmltree::{Element, XMLNode};
+
+/// Fetch one of the speaker's `/status/...` support pages, all of which are `<ZPSupportInfo>`
+/// documents. These aren't part of UPnP and only some models/firmware serve each page.
+pub async fn fetch(ip: IpAddr, page: &str) -> Fallible<Element> {
+    let response = reqwest::get(&format!("{}/status/{}", crate::upnp::base_url(ip), page)).await?;
+
+    if !response.status().is_success() {
+        bail!("Speaker returned {} for /status/{}", response.status(), page);
+    }
+
+    Ok(Element::parse(response.bytes().await?.as_ref())?)
+}
+
+#[derive(Debug, Default)]
+pub struct BatteryStatus {
+    pub level: Option<u8>,
+    pub health: Option<String>,
+    pub temperature: Option<String>,
+    pub power_source: Option<String>,
+}
+
+/// Battery status of portable speakers, `None` for speakers without a battery.
+pub async fn battery(ip: IpAddr) -> Fallible<Option<BatteryStatus>> {
+    let info = fetch(ip, "batterystatus").await?;
+
+    let battery = match info.get_child("LocalBatteryStatus") {
+        Some(battery) => battery,
+        None => return Ok(None),
+    };
+
+    let mut status = BatteryStatus::default();
+
+    for data in battery.children.iter().filter_map(XMLNode::as_element) {
+        let value = data.get_text().map(|t| t.into_owned());
+
+        match data.attributes.get("name").map(String::as_str) {
+            Some("Level") => status.level = value.and_then(|v| v.parse().ok()),
+            Some("Health") => status.health = value,
+            Some("Temperature") => status.temperature = value,
+            Some("PowerSource") => status.power_source = value,
+            _ => {},
+        }
+    }
+
+    Ok(Some(status))
+}
+
+/// Time since the speaker last booted.
+pub async fn uptime(ip: IpAddr) -> Fallible<Duration> {
+    let file = file_contents(&fetch(ip, "proc/uptime").await?);
+
+    let secs = file.split_whitespace()
+        .next()
+        .and_then(|v| v.parse::<f64>().ok())
+        .ok_or_else(|| format_err!("Couldn't parse speaker uptime"))?;
+
+    Ok(Duration::from_secs(secs as u64))
+}
+
+#[derive(Debug, Default)]
+pub struct WirelessStatus {
+    pub ssid: Option<String>,
+    pub rssi: Option<i32>,
+}
+
+/// Details of the speaker's wireless connection, fields are `None` when they aren't reported.
+pub async fn wireless(ip: IpAddr) -> Fallible<WirelessStatus> {
+    let file = file_contents(&fetch(ip, "proc/ath_rincon/status").await?);
+
+    Ok(WirelessStatus {
+        ssid: field(&file, "SSID").map(String::from),
+        rssi: field(&file, "RSSI").and_then(|v| v.parse().ok()),
+    })
+}
+
+/// Whether the speaker has a running ethernet interface.
+pub async fn wired(ip: IpAddr) -> Fallible<bool> {
+    let ifconfig = file_contents(&fetch(ip, "ifconfig").await?);
+
+    // interfaces are separated by a blank line, each starting with the interface name
+    Ok(ifconfig.split("\n\n")
+        .map(str::trim_start)
+        .any(|iface| iface.starts_with("eth") && iface.contains("RUNNING")))
+}
+
+/// Value of the first `key: value`/`key=value` line containing `key`.
+fn field<'a>(file: &'a str, key: &str) -> Option<&'a str> {
+    file.lines()
+        .filter_map(|line| line.find(key).map(|i| &line[i + key.len()..]))
+        .map(|rest| rest.trim_start_matches(|c: char| c == ':' || c == '=' || c.is_whitespace()).trim())
+        .find(|value| !value.is_empty())
+}
+
+/// `/status/proc/...` pages wrap the raw file in a `<File>` element.
+fn file_contents(info: &Element) -> String {
+    info.get_child("File")
+        .and_then(|f| f.get_text())
+        .map(|t| t.into_owned())
+        .unwrap_or_default()
+}
@@ -0,0 +1,237 @@
+use std::net::IpAddr;
+
+use sonos::Speaker;
+use failure::Fallible;
+use xmltree::Element;
+
+use crate::transport;
+use crate::upnp::{self, Service};
+
+#[derive(Debug)]
+pub struct ZoneMember {
+    pub uuid: String,
+    pub name: String,
+    pub ip: IpAddr,
+    /// Bonded devices (subs, surrounds, the second half of a stereo pair) are invisible
+    pub invisible: bool,
+    /// Home theatre satellites (subs and surrounds) bonded to this device
+    pub satellites: Vec<ZoneMember>,
+    /// `ChannelMapSet`/`HTSatChanMapSet` describing the role of each bonded device
+    pub channel_map: Option<String>,
+}
+impl ZoneMember {
+    /// Role of a bonded device within this member's room, from the channel map
+    /// (eg. `RINCON_A:LF,RF;RINCON_B:SW`).
+    pub fn role_of(&self, uuid: &str) -> Option<&'static str> {
+        let channels = self.channel_map.as_ref()?
+            .split(';')
+            .find(|entry| entry.starts_with(uuid))?
+            .split(':')
+            .nth(1)?;
+
+        Some(match channels {
+            "SW" => "sub",
+            "LR" => "left surround",
+            "RR" => "right surround",
+            "LF,LF" => "left",
+            "RF,RF" => "right",
+            _ => "main",
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct ZoneGroup {
+    pub coordinator: String,
+    pub members: Vec<ZoneMember>,
+}
+impl ZoneGroup {
+    pub fn coordinator(&self) -> Option<&ZoneMember> {
+        self.members.iter().find(|m| m.uuid == self.coordinator)
+    }
+
+    pub fn contains(&self, uuid: &str) -> bool {
+        self.members.iter().any(|m| m.uuid == uuid)
+    }
+
+    /// Devices bonded into the given member's room: its home theatre satellites and the other
+    /// half of a stereo pair.
+    pub fn bonded<'a>(&'a self, member: &'a ZoneMember) -> Vec<&'a ZoneMember> {
+        let mut bonded = member.satellites.iter().collect::<Vec<_>>();
+
+        for other in &self.members {
+            if other.invisible && other.name == member.name && other.uuid != member.uuid
+                    && !bonded.iter().any(|b| b.uuid == other.uuid) {
+                bonded.push(other);
+            }
+        }
+
+        bonded
+    }
+}
+
+/// Fetch the household's zone group topology as seen by the given speaker.
+pub async fn zone_groups(speaker: &Speaker) -> Fallible<Vec<ZoneGroup>> {
+    let response = upnp::call(speaker.ip, Service::ZoneGroupTopology, "GetZoneGroupState", &[]).await?;
+    let state = upnp::child_text(&response, "ZoneGroupState")
+        .ok_or_else(|| format_err!("GetZoneGroupState response is missing ZoneGroupState"))?;
+
+    parse_zone_groups(&state)
+}
+
+/// Parse the `ZoneGroupState` document returned by `GetZoneGroupState`.
+pub fn parse_zone_groups(state: &str) -> Fallible<Vec<ZoneGroup>> {
+    let state = Element::parse(state.as_bytes())?;
+
+    // newer firmware wraps the groups in a <ZoneGroupState> root element
+    let groups = state.get_child("ZoneGroups").unwrap_or(&state);
+
+    upnp::elements(groups, "ZoneGroup")
+        .map(|group| Ok(ZoneGroup {
+            coordinator: upnp::attribute(group, "Coordinator")?,
+            members: upnp::elements(group, "ZoneGroupMember").map(parse_member).collect::<Fallible<_>>()?,
+        }))
+        .collect()
+}
+
+/// Fetch the household's zone group topology from the first of the given speakers that
+/// responds, every speaker knows about the whole household so one query is all it takes.
+pub async fn household_groups(speakers: &[Speaker]) -> Fallible<Vec<ZoneGroup>> {
+    let mut last_error = None;
+
+    for speaker in speakers {
+        match zone_groups(speaker).await {
+            Ok(groups) => return Ok(groups),
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| not_found!("Couldn't find any speakers")))
+}
+
+/// The group the given speaker is currently part of.
+pub async fn group_of(speaker: &Speaker) -> Fallible<ZoneGroup> {
+    zone_groups(speaker).await?
+        .into_iter()
+        .find(|g| g.contains(&speaker.uuid))
+        .ok_or_else(|| format_err!("{} isn't part of any group", speaker.name))
+}
+
+/// Join the speaker to the group coordinated by the speaker with the given UUID.
+pub async fn join(speaker: &Speaker, coordinator: &str) -> Fallible<()> {
+    transport::set_uri(speaker.ip, &format!("x-rincon:{}", coordinator), "").await
+}
+
+/// Take the speaker out of its group, making it the coordinator of its own group.
+pub async fn leave(speaker: &Speaker) -> Fallible<()> {
+    upnp::call(speaker.ip, Service::AVTransport, "BecomeCoordinatorOfStandaloneGroup", &[
+        ("InstanceID", "0"),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Bond two speakers of the same model into a stereo pair, the left speaker becomes the room.
+pub async fn create_stereo_pair(left: &Speaker, right: &Speaker) -> Fallible<()> {
+    upnp::call(left.ip, Service::DeviceProperties, "CreateStereoPair", &[
+        ("ChannelMapSet", &format!("{}:LF,LF;{}:RF,RF", left.uuid, right.uuid)),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Split a stereo pair back into two rooms, must be called on the left speaker of the pair.
+pub async fn separate_stereo_pair(left: IpAddr, channel_map: &str) -> Fallible<()> {
+    upnp::call(left, Service::DeviceProperties, "SeparateStereoPair", &[
+        ("ChannelMapSet", channel_map),
+    ]).await?;
+
+    Ok(())
+}
+
+/// The stereo pair the speaker with the given UUID is part of as `(channel map, left, right)`.
+pub fn stereo_pair<'a>(group: &'a ZoneGroup, uuid: &str) -> Option<(&'a str, &'a ZoneMember, &'a ZoneMember)> {
+    let map = group.members.iter()
+        .filter_map(|m| m.channel_map.as_deref())
+        .find(|map| map.contains(uuid) && map.contains("LF,LF"))?;
+
+    let speaker_for = |channels: &str| {
+        let uuid = map.split(';').find(|e| e.ends_with(channels))?.split(':').next()?;
+        group.members.iter().find(|m| m.uuid == uuid)
+    };
+
+    Some((map, speaker_for("LF,LF")?, speaker_for("RF,RF")?))
+}
+
+/// Bond a pair of surrounds to a soundbar, keeping any sub that's already bonded to it.
+pub async fn add_surrounds(soundbar: &ZoneMember, left: &Speaker, right: &Speaker) -> Fallible<()> {
+    let sub = soundbar.channel_map.as_deref()
+        .and_then(|map| map.split(';').find(|e| e.ends_with(":SW")))
+        .map(|e| format!(";{}", e))
+        .unwrap_or_default();
+
+    upnp::call(soundbar.ip, Service::DeviceProperties, "AddHTSatellite", &[
+        ("HTSatChanMapSet", &format!("{}:LF,RF{};{}:LR;{}:RR", soundbar.uuid, sub, left.uuid, right.uuid)),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Unbond a satellite from the soundbar it's bonded to.
+pub async fn remove_satellite(soundbar: IpAddr, satellite: &str) -> Fallible<()> {
+    upnp::call(soundbar, Service::DeviceProperties, "RemoveHTSatellite", &[
+        ("SatRoomUUID", satellite),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Rename the speaker's room, returning its old name.
+pub async fn rename(ip: IpAddr, name: &str) -> Fallible<String> {
+    let attributes = upnp::call(ip, Service::DeviceProperties, "GetZoneAttributes", &[]).await?;
+    let current = |field| upnp::child_text(&attributes, field).unwrap_or_default();
+
+    upnp::call(ip, Service::DeviceProperties, "SetZoneAttributes", &[
+        ("DesiredZoneName", name),
+        ("DesiredIcon", &current("CurrentIcon")),
+        ("DesiredConfiguration", &current("CurrentConfiguration")),
+    ]).await?;
+
+    Ok(current("CurrentZoneName"))
+}
+
+/// Volume of the whole group, must be called on the group's coordinator.
+pub async fn group_volume(ip: IpAddr) -> Fallible<u8> {
+    let response = upnp::call(ip, Service::GroupRenderingControl, "GetGroupVolume", &[("InstanceID", "0")]).await?;
+
+    upnp::child_text(&response, "CurrentVolume")
+        .and_then(|v| v.parse().ok())
+        .ok_or_else(|| format_err!("GetGroupVolume response is missing CurrentVolume"))
+}
+
+fn parse_member(el: &Element) -> Fallible<ZoneMember> {
+    let location = upnp::attribute(el, "Location")?;
+
+    Ok(ZoneMember {
+        uuid: upnp::attribute(el, "UUID")?,
+        name: upnp::attribute(el, "ZoneName")?,
+        ip: ip_from_location(&location)
+            .ok_or_else(|| format_err!("Couldn't parse speaker location {}", location))?,
+        invisible: el.attributes.get("Invisible").map(|v| v == "1").unwrap_or(false),
+        satellites: upnp::elements(el, "Satellite").map(parse_member).collect::<Fallible<_>>()?,
+        channel_map: el.attributes.get("HTSatChanMapSet")
+            .or_else(|| el.attributes.get("ChannelMapSet"))
+            .cloned(),
+    })
+}
+
+/// Grab the IP address out of a location such as `http://192.168.1.2:1400/xml/device_description.xml`
+/// or `http://[fd00::1]:1400/xml/device_description.xml`
+fn ip_from_location(location: &str) -> Option<IpAddr> {
+    let authority = location.split("//").nth(1)?.split('/').next()?;
+
+    match authority.strip_prefix('[') {
+        Some(v6) => v6.split(']').next()?.parse().ok(),
+        None => authority.split(':').next()?.parse().ok(),
+    }
+}
@@ -0,0 +1,161 @@
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use failure::Fallible;
+
+use crate::upnp::{self, Service};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct NowPlaying {
+    pub artist: String,
+    pub title: String,
+}
+
+pub struct MediaInfo {
+    pub uri: String,
+    pub metadata: String,
+}
+
+/// Load the given URI into the speaker's transport, replacing whatever was playing.
+pub async fn set_uri(ip: IpAddr, uri: &str, metadata: &str) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "SetAVTransportURI", &[
+        ("InstanceID", "0"),
+        ("CurrentURI", uri),
+        ("CurrentURIMetaData", metadata),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Start or resume playback.
+pub async fn play(ip: IpAddr) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Play", &[("InstanceID", "0"), ("Speed", "1")]).await?;
+
+    Ok(())
+}
+
+pub async fn pause(ip: IpAddr) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Pause", &[("InstanceID", "0")]).await?;
+
+    Ok(())
+}
+
+pub async fn stop(ip: IpAddr) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Stop", &[("InstanceID", "0")]).await?;
+
+    Ok(())
+}
+
+/// Skip to the given track of the queue, numbered from 1.
+pub async fn skip_to(ip: IpAddr, track: u64) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Seek", &[
+        ("InstanceID", "0"),
+        ("Unit", "TRACK_NR"),
+        ("Target", &track.to_string()),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Seek to a position within the current track.
+pub async fn seek(ip: IpAddr, to: Duration) -> Fallible<()> {
+    let target = format!("{:02}:{:02}:{:02}", to.as_secs() / 3600, to.as_secs() % 3600 / 60, to.as_secs() % 60);
+
+    upnp::call(ip, Service::AVTransport, "Seek", &[
+        ("InstanceID", "0"),
+        ("Unit", "REL_TIME"),
+        ("Target", &target),
+    ]).await?;
+
+    Ok(())
+}
+
+pub async fn next(ip: IpAddr) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Next", &[("InstanceID", "0")]).await?;
+
+    Ok(())
+}
+
+pub async fn previous(ip: IpAddr) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "Previous", &[("InstanceID", "0")]).await?;
+
+    Ok(())
+}
+
+/// Current transport state as reported by the speaker, eg. `PLAYING` or `PAUSED_PLAYBACK`.
+pub async fn state(ip: IpAddr) -> Fallible<String> {
+    let info = upnp::call(ip, Service::AVTransport, "GetTransportInfo", &[("InstanceID", "0")]).await?;
+
+    upnp::child_text(&info, "CurrentTransportState")
+        .ok_or_else(|| format_err!("GetTransportInfo response is missing CurrentTransportState"))
+}
+
+/// Poll the transport state until `done` is happy with it, failing if that hasn't happened
+/// within `timeout`.
+pub async fn wait_for_state(ip: IpAddr, timeout: Duration, expected: &str, done: impl Fn(&str) -> bool) -> Fallible<()> {
+    let deadline = Instant::now() + timeout;
+
+    // nothing was actually sent, so there's nothing to wait for
+    if upnp::dry_run() {
+        return Ok(());
+    }
+
+    loop {
+        let state = state(ip).await?;
+
+        if done(&state) {
+            return Ok(());
+        }
+
+        if Instant::now() >= deadline {
+            bail!("Speaker didn't reach {} within {}s, it's still {}", expected, timeout.as_secs(), state);
+        }
+
+        tokio::time::delay_for(Duration::from_millis(250)).await;
+    }
+}
+
+/// The URI loaded into the speaker's transport along with its metadata, the URI is empty if
+/// nothing is loaded.
+pub async fn media_info(ip: IpAddr) -> Fallible<MediaInfo> {
+    let media = upnp::call(ip, Service::AVTransport, "GetMediaInfo", &[("InstanceID", "0")]).await?;
+
+    Ok(MediaInfo {
+        uri: upnp::child_text(&media, "CurrentURI").unwrap_or_default(),
+        metadata: upnp::child_text(&media, "CurrentURIMetaData").unwrap_or_default(),
+    })
+}
+
+/// Current play mode of the speaker, eg. `NORMAL`, `REPEAT_ALL` or `SHUFFLE_NOREPEAT`.
+pub async fn play_mode(ip: IpAddr) -> Fallible<String> {
+    let settings = upnp::call(ip, Service::AVTransport, "GetTransportSettings", &[("InstanceID", "0")]).await?;
+
+    upnp::child_text(&settings, "PlayMode")
+        .ok_or_else(|| format_err!("GetTransportSettings response is missing PlayMode"))
+}
+
+pub async fn set_play_mode(ip: IpAddr, mode: &str) -> Fallible<()> {
+    upnp::call(ip, Service::AVTransport, "SetPlayMode", &[
+        ("InstanceID", "0"),
+        ("NewPlayMode", mode),
+    ]).await?;
+
+    Ok(())
+}
+
+/// The track loaded on the speaker, `None` if there's nothing loaded.
+pub async fn now_playing(ip: IpAddr) -> Fallible<Option<NowPlaying>> {
+    let info = upnp::call(ip, Service::AVTransport, "GetPositionInfo", &[("InstanceID", "0")]).await?;
+
+    let metadata = match upnp::child_text(&info, "TrackMetaData") {
+        Some(metadata) if metadata.starts_with('<') => metadata,
+        _ => return Ok(None),
+    };
+
+    Ok(upnp::didl_items(&metadata)?
+        .first()
+        .map(|item| NowPlaying {
+            artist: upnp::child_text(item, "creator").unwrap_or_default(),
+            title: upnp::child_text(item, "title").unwrap_or_default(),
+        }))
+}
@@ -0,0 +1,280 @@
+use std::net::IpAddr;
+
+use failure::Fallible;
+use xmltree::{Element, EmitterConfig, XMLNode};
+
+/// UPnP services exposed by a Sonos speaker that we call into directly.
+#[derive(Debug, Clone, Copy)]
+pub enum Service {
+    AVTransport,
+    RenderingControl,
+    GroupRenderingControl,
+    ContentDirectory,
+    ZoneGroupTopology,
+    DeviceProperties,
+    AlarmClock,
+    SystemProperties,
+    AudioIn,
+}
+
+impl Service {
+    const ALL: &'static [Service] = &[
+        Service::AVTransport,
+        Service::RenderingControl,
+        Service::GroupRenderingControl,
+        Service::ContentDirectory,
+        Service::ZoneGroupTopology,
+        Service::DeviceProperties,
+        Service::AlarmClock,
+        Service::SystemProperties,
+        Service::AudioIn,
+    ];
+
+    fn endpoint(self) -> &'static str {
+        match self {
+            Service::AVTransport => "MediaRenderer/AVTransport/Control",
+            Service::RenderingControl => "MediaRenderer/RenderingControl/Control",
+            Service::GroupRenderingControl => "MediaRenderer/GroupRenderingControl/Control",
+            Service::ContentDirectory => "MediaServer/ContentDirectory/Control",
+            Service::ZoneGroupTopology => "ZoneGroupTopology/Control",
+            Service::DeviceProperties => "DeviceProperties/Control",
+            Service::AlarmClock => "AlarmClock/Control",
+            Service::SystemProperties => "SystemProperties/Control",
+            Service::AudioIn => "AudioIn/Control",
+        }
+    }
+
+    fn urn(self) -> String {
+        format!("urn:schemas-upnp-org:service:{:?}:1", self)
+    }
+}
+
+impl std::str::FromStr for Service {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Fallible<Service> {
+        Service::ALL.iter()
+            .copied()
+            .find(|service| format!("{:?}", service).eq_ignore_ascii_case(s))
+            .ok_or_else(|| invalid_argument!(
+                "Unknown service {}, expected one of: {}",
+                s,
+                Service::ALL.iter().map(|service| format!("{:?}", service)).collect::<Vec<_>>().join(", "),
+            ))
+    }
+}
+
+#[derive(Debug, Fail)]
+#[fail(display = "{} failed with UPnP error {}", action, code)]
+pub struct Fault {
+    pub action: String,
+    pub code: u32,
+}
+
+/// The speaker responded with an HTTP error and no UPnP fault explaining it.
+#[derive(Debug, Fail)]
+#[fail(display = "{} failed with HTTP status {}", action, status)]
+pub struct HttpError {
+    pub action: String,
+    pub status: u16,
+}
+
+static DRY_RUN: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Record actions that would change something instead of sending them, set by `--dry-run`.
+/// Reads still go to the speaker since commands need them to work out what to do.
+pub fn set_dry_run(dry_run: bool) {
+    DRY_RUN.store(dry_run, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn dry_run() -> bool {
+    DRY_RUN.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// An action that would have been sent if it wasn't for `--dry-run`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlannedAction {
+    pub ip: IpAddr,
+    pub service: String,
+    pub action: String,
+    pub arguments: Vec<(String, String)>,
+}
+impl std::fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "{} {}#{}", self.ip, self.service, self.action)?;
+
+        for (name, value) in &self.arguments {
+            write!(f, " {}={:?}", name, value)?;
+        }
+
+        Ok(())
+    }
+}
+
+static PLANNED: std::sync::Mutex<Vec<PlannedAction>> = std::sync::Mutex::new(Vec::new());
+
+/// Every action held back by `--dry-run` so far, in the order they would have been sent.
+pub fn planned() -> Vec<PlannedAction> {
+    std::mem::take(&mut *PLANNED.lock().unwrap())
+}
+
+/// Whether an action only reads state, going by the naming every Sonos service sticks to.
+fn is_read(action: &str) -> bool {
+    ["Get", "List", "Browse", "Search"].iter().any(|prefix| action.starts_with(prefix))
+}
+
+/// Call `action` on the given service of the speaker at `ip`, returning the `<{action}Response>`
+/// element. Under `--dry-run` anything but a read is recorded and an empty response returned.
+pub async fn call(ip: IpAddr, service: Service, action: &str, args: &[(&str, &str)]) -> Fallible<Element> {
+    if dry_run() && !is_read(action) {
+        PLANNED.lock().unwrap().push(PlannedAction {
+            ip,
+            service: format!("{:?}", service),
+            action: action.to_string(),
+            arguments: args.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect(),
+        });
+
+        return Ok(Element::new(&format!("{}Response", action)));
+    }
+
+    let payload = args.iter()
+        .map(|(k, v)| format!("<{k}>{v}</{k}>", k = k, v = escape(v)))
+        .collect::<String>();
+
+    let body = format!(
+        r#"<s:Envelope xmlns:s="http://schemas.xmlsoap.org/soap/envelope/" s:encodingStyle="http://schemas.xmlsoap.org/soap/encoding/"><s:Body><u:{action} xmlns:u="{service}">{payload}</u:{action}></s:Body></s:Envelope>"#,
+        action = action,
+        service = service.urn(),
+        payload = payload,
+    );
+
+    debug!("Running {}#{} on {}", service.urn(), action, ip);
+
+    let response = reqwest::Client::new()
+        .post(&format!("{}/{}", base_url(ip), service.endpoint()))
+        .header("Content-Type", "text/xml; charset=\"utf-8\"")
+        .header("SOAPAction", format!("\"{}#{}\"", service.urn(), action))
+        .body(body)
+        .send()
+        .await?;
+
+    let status = response.status();
+    let response = response.bytes().await?;
+
+    let envelope = match Element::parse(response.as_ref()) {
+        Ok(envelope) => envelope,
+        Err(_) if !status.is_success() => {
+            return Err(HttpError { action: action.to_string(), status: status.as_u16() }.into());
+        },
+        Err(e) => return Err(e.into()),
+    };
+    let body = envelope.get_child("Body")
+        .ok_or_else(|| format_err!("{} returned a response without a body", action))?;
+
+    if let Some(fault) = body.get_child("Fault") {
+        let code = fault.get_child("detail")
+            .and_then(|d| d.get_child("UPnPError"))
+            .and_then(|e| child_text(e, "errorCode"))
+            .and_then(|c| c.parse().ok())
+            .unwrap_or(0);
+
+        return Err(Fault { action: action.to_string(), code }.into());
+    }
+
+    Ok(body.get_child(format!("{}Response", action))
+        .ok_or_else(|| format_err!("{} returned an unexpected response", action))?
+        .clone())
+}
+
+/// Service types (eg. `urn:schemas-upnp-org:service:AudioIn:1`) the speaker advertises in its
+/// device description, including those of its embedded devices.
+pub async fn services(ip: IpAddr) -> Fallible<Vec<String>> {
+    fn collect(el: &Element, services: &mut Vec<String>) {
+        if el.name == "serviceType" {
+            services.extend(el.get_text().map(|t| t.into_owned()));
+        }
+
+        for child in el.children.iter().filter_map(XMLNode::as_element) {
+            collect(child, services);
+        }
+    }
+
+    let description = reqwest::get(&format!("{}/xml/device_description.xml", base_url(ip)))
+        .await?
+        .bytes()
+        .await?;
+
+    let mut services = Vec::new();
+    collect(&Element::parse(description.as_ref())?, &mut services);
+
+    Ok(services)
+}
+
+/// Text content of the named child of `el`, if it exists.
+pub fn child_text(el: &Element, name: &str) -> Option<String> {
+    el.get_child(name)
+        .and_then(|c| c.get_text())
+        .map(|t| t.into_owned())
+}
+
+/// Value of the named attribute of `el`, failing if it's missing.
+pub fn attribute(el: &Element, name: &str) -> Fallible<String> {
+    el.attributes.get(name)
+        .cloned()
+        .ok_or_else(|| format_err!("<{}> is missing the {} attribute", el.name, name))
+}
+
+/// Children of `el` with the given name.
+pub fn elements<'a>(el: &'a Element, name: &'a str) -> impl Iterator<Item = &'a Element> + 'a {
+    el.children.iter()
+        .filter_map(XMLNode::as_element)
+        .filter(move |c| c.name == name)
+}
+
+/// Parse a DIDL-Lite document, returning its `<item>`s.
+pub fn didl_items(didl: &str) -> Fallible<Vec<Element>> {
+    Ok(Element::parse(didl.as_bytes())?
+        .children
+        .into_iter()
+        .filter_map(|node| match node {
+            XMLNode::Element(el) if el.name == "item" => Some(el),
+            _ => None,
+        })
+        .collect())
+}
+
+/// Wrap a single `<item>` back up in a DIDL-Lite document, ie. to pass an item we browsed on to
+/// another speaker as metadata.
+pub fn didl_document(item: &Element) -> Fallible<String> {
+    let mut didl = Element::new("DIDL-Lite");
+    didl.namespace = item.namespace.clone();
+    didl.namespaces = item.namespaces.clone();
+    didl.children.push(XMLNode::Element(item.clone()));
+
+    let mut out = Vec::new();
+    didl.write_with_config(&mut out, EmitterConfig::new().write_document_declaration(false))?;
+
+    Ok(String::from_utf8(out)?)
+}
+
+/// Root URL of the speaker's web server, with IPv6 addresses bracketed (`http://[fd00::1]:1400`).
+pub fn base_url(ip: IpAddr) -> String {
+    format!("http://{}", std::net::SocketAddr::new(ip, 1400))
+}
+
+/// Resolve a path served by the speaker (ie. album art from `/getaa?...`) to a full URL.
+pub fn resolve_url(ip: IpAddr, path: &str) -> String {
+    if path.starts_with('/') {
+        format!("{}{}", base_url(ip), path)
+    } else {
+        path.to_string()
+    }
+}
+
+pub fn escape(s: &str) -> String {
+    s.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
@@ -1,13 +1,789 @@
 macro_rules! print_struct {
     ($args:ident, $struc:expr) => {{
-        info!("{}", if $args.is_present("json") {
-            serde_json::to_string($struc)?
+        if !$args.is_present("json") && !$crate::util::json() && $crate::util::delimited().is_none() {
+            $crate::util::output(&$struc.to_string())?
+        } else if let Some(delimited) = $crate::util::delimited() {
+            $crate::util::output(&delimited.render(&serde_json::to_value($struc)?))?
         } else {
-            $struc.to_string()
-        })
+            $crate::util::output_json(&$crate::util::Envelope::data($struc))?
+        }
     }}
 }
 
+/// Like `format_err!`, for something that was asked for by name or ID but doesn't exist.
+macro_rules! not_found {
+    ($($arg:tt)*) => { failure::Error::from($crate::util::NotFound(format!($($arg)*))) }
+}
+
+/// Like `format_err!`, for an argument or option that can't be used as given.
+macro_rules! invalid_argument {
+    ($($arg:tt)*) => { failure::Error::from($crate::util::InvalidArgument(format!($($arg)*))) }
+}
+
+static OUTPUT: std::sync::Mutex<Option<std::fs::File>> = std::sync::Mutex::new(None);
+
+/// Write results to the file at `path` instead of stdout, set by `--output`. The file is
+/// truncated unless `append` is set.
+pub fn set_output(path: &str, append: bool) -> failure::Fallible<()> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|e| format_err!("Couldn't open {} for writing: {}", path, e))?;
+
+    *OUTPUT.lock().unwrap() = Some(file);
+
+    Ok(())
+}
+
+/// Print a command's result to stdout, or the `--output` file if one was given. This is the only
+/// thing that should ever write to stdout.
+pub fn output(result: &str) -> failure::Fallible<()> {
+    use std::io::Write;
+
+    // --json --quiet still outputs the JSON, that's the whole point of asking for it
+    if quiet() && !json() && delimited().is_none() {
+        return Ok(());
+    }
+
+    match OUTPUT.lock().unwrap().as_mut() {
+        Some(file) => writeln!(file, "{}", result)?,
+        None => writeln!(std::io::stdout().lock(), "{}", result)?,
+    }
+
+    Ok(())
+}
+
+static HELD: std::sync::Mutex<Option<serde_json::Value>> = std::sync::Mutex::new(None);
+
+/// Print a `--json` result. Under `--dry-run` it's held back until the command has finished
+/// instead, so the actions that would have been sent can go out in the same document.
+pub fn output_json(envelope: &impl serde::Serialize) -> failure::Fallible<()> {
+    if crate::upnp::dry_run() {
+        *HELD.lock().unwrap() = Some(serde_json::to_value(envelope)?);
+        return Ok(());
+    }
+
+    output(&to_json(envelope)?)
+}
+
+/// The `--json` result `output_json` held back for `--dry-run`, if the command had one.
+pub fn take_held_json() -> Option<serde_json::Value> {
+    HELD.lock().unwrap().take()
+}
+
+/// Tell the user something about how a command was carried out, on stderr so it's never
+/// mistaken for the command's result. Nothing is printed with `--quiet`.
+pub fn note(message: &str) {
+    if !quiet() {
+        eprintln!("{}", message);
+    }
+}
+
+static QUIET: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Print nothing but errors and JSON, set by `--quiet`.
+pub fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn quiet() -> bool {
+    QUIET.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Per-user directory for everything we cache, ie. `~/.cache/sonos-cli` on Linux.
+pub fn cache_dir() -> std::path::PathBuf {
+    dirs::cache_dir()
+        .unwrap_or_else(std::env::temp_dir)
+        .join("sonos-cli")
+}
+
+static JSON: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Output results as JSON, set by `--json` or the config file.
+pub fn set_json(json: bool) {
+    JSON.store(json, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn json() -> bool {
+    JSON.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Rows of delimited values for spreadsheets and `awk`, picked with `--output-format`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Delimited {
+    Tsv,
+    Csv,
+}
+impl std::str::FromStr for Delimited {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "tsv" => Ok(Delimited::Tsv),
+            "csv" => Ok(Delimited::Csv),
+            _ => Err(invalid_argument!("Expected an output format of tsv or csv, got {}", s)),
+        }
+    }
+}
+impl Delimited {
+    /// Render the same value `--json` would print as rows, one per item of the list in it (a
+    /// single row if it isn't a list). Nested objects become `parent.child` columns, and anything
+    /// that still doesn't fit in a cell is written as JSON.
+    pub fn render(self, value: &serde_json::Value) -> String {
+        use serde_json::Value;
+
+        fn flatten(prefix: &str, value: &Value, row: &mut Vec<(String, String)>) {
+            match value {
+                Value::Object(fields) => for (key, value) in fields {
+                    let key = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+                    flatten(&key, value, row);
+                },
+                Value::Null => row.push((prefix.to_string(), String::new())),
+                Value::String(s) => row.push((prefix.to_string(), s.clone())),
+                other => row.push((prefix.to_string(), other.to_string())),
+            }
+        }
+
+        let is_records = |value: &Value| value.as_array().is_some_and(|items| items.iter().all(Value::is_object));
+
+        // lists usually come wrapped up with what was asked for, ie. a track list's filters
+        let items = match value {
+            Value::Array(items) => items.iter().collect::<Vec<_>>(),
+            Value::Object(fields) => match fields.values().find(|v| is_records(v)) {
+                Some(Value::Array(items)) => items.iter().collect(),
+                _ => vec![value],
+            },
+            value => vec![value],
+        };
+
+        let rows = items.into_iter()
+            .map(|item| {
+                let mut row = Vec::new();
+                flatten("", item, &mut row);
+                row
+            })
+            .collect::<Vec<_>>();
+
+        let mut columns: Vec<&str> = Vec::new();
+
+        for (column, _) in rows.iter().flatten() {
+            if !columns.contains(&column.as_str()) {
+                columns.push(column);
+            }
+        }
+
+        let mut lines = Vec::new();
+
+        if !NO_HEADER.load(std::sync::atomic::Ordering::Relaxed) {
+            lines.push(self.line(columns.iter().map(|c| if c.is_empty() { "value" } else { c })));
+        }
+
+        for row in &rows {
+            lines.push(self.line(columns.iter().map(|column| row.iter()
+                .find(|(name, _)| name == column)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or(""))));
+        }
+
+        lines.join("\n")
+    }
+
+    fn line<'a>(self, cells: impl Iterator<Item = &'a str>) -> String {
+        match self {
+            // TSV has no quoting, so anything that'd split the cell up is swapped for a space
+            Delimited::Tsv => cells
+                .map(|cell| cell.replace(['\t', '\n', '\r'], " "))
+                .collect::<Vec<_>>()
+                .join("\t"),
+            Delimited::Csv => cells
+                .map(|cell| if cell.contains([',', '"', '\n', '\r']) {
+                    format!("\"{}\"", cell.replace('"', "\"\""))
+                } else {
+                    cell.to_string()
+                })
+                .collect::<Vec<_>>()
+                .join(","),
+        }
+    }
+}
+
+static DELIMITED: std::sync::Mutex<Option<Delimited>> = std::sync::Mutex::new(None);
+
+/// Output results as TSV or CSV, set by `--output-format`.
+pub fn set_delimited(delimited: Option<Delimited>) {
+    *DELIMITED.lock().unwrap() = delimited;
+}
+
+pub fn delimited() -> Option<Delimited> {
+    *DELIMITED.lock().unwrap()
+}
+
+static NO_HEADER: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Leave the header row off TSV and CSV output, set by `--no-header`.
+pub fn set_no_header(no_header: bool) {
+    NO_HEADER.store(no_header, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Version of the shape of `--json` output, bumped whenever a field is renamed or removed or
+/// changes type so tooling can tell it's reading something it doesn't understand. Adding fields
+/// doesn't need a bump.
+pub const SCHEMA_VERSION: u32 = 1;
+
+static COMMAND: std::sync::Mutex<String> = std::sync::Mutex::new(String::new());
+
+/// The subcommand being run, ie. `track list`, for the JSON envelope.
+pub fn set_command(command: String) {
+    *COMMAND.lock().unwrap() = command;
+}
+
+/// What every `--json` result is wrapped in, so stdout is always one document with either `data`
+/// or `error` in it. Under `--dry-run` the actions that would have been sent are in `planned`.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Envelope<T> {
+    pub schema: u32,
+    pub command: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<T>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ErrorDetail>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub planned: Option<Vec<crate::upnp::PlannedAction>>,
+}
+impl<T> Envelope<T> {
+    pub fn data(data: T) -> Self {
+        Envelope { schema: SCHEMA_VERSION, command: COMMAND.lock().unwrap().clone(), data: Some(data), error: None, planned: None }
+    }
+}
+impl Envelope<()> {
+    pub fn error(kind: &str, message: String) -> Self {
+        Envelope {
+            schema: SCHEMA_VERSION,
+            command: COMMAND.lock().unwrap().clone(),
+            data: None,
+            error: Some(ErrorDetail { kind: kind.to_string(), message }),
+            planned: None,
+        }
+    }
+
+    /// The result of a `--dry-run` command that doesn't print anything of its own.
+    pub fn planned(planned: Vec<crate::upnp::PlannedAction>) -> Self {
+        Envelope { schema: SCHEMA_VERSION, command: COMMAND.lock().unwrap().clone(), data: None, error: None, planned: Some(planned) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ErrorDetail {
+    /// `ambiguous`, `not_found`, `invalid_argument`, `network`, `upnp_fault` or `error`
+    pub kind: String,
+    pub message: String,
+}
+
+/// Something that was asked for by name or ID doesn't exist, see `not_found!`.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct NotFound(pub String);
+
+/// An argument or option that can't be used as given, see `invalid_argument!`.
+#[derive(Debug, Fail)]
+#[fail(display = "{}", _0)]
+pub struct InvalidArgument(pub String);
+
+/// A speaker didn't respond within `--timeout`.
+#[derive(Debug, Fail)]
+#[fail(display = "Operation timed out after {}s", _0)]
+pub struct TimedOut(pub u64);
+
+/// Serialise `value` as JSON, indented if `--pretty` was given.
+pub fn to_json(value: &impl serde::Serialize) -> serde_json::Result<String> {
+    if pretty() {
+        serde_json::to_string_pretty(value)
+    } else {
+        serde_json::to_string(value)
+    }
+}
+
+static PRETTY: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Indent JSON output for people to read, set by `--pretty`.
+pub fn set_pretty(pretty: bool) {
+    PRETTY.store(pretty, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn pretty() -> bool {
+    PRETTY.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+static ASCII: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Stick to plain ASCII in human readable output, set by `--ascii`, the config file's `no_emoji`
+/// or a locale that isn't UTF-8.
+pub fn set_ascii(ascii: bool) {
+    ASCII.store(ascii, std::sync::atomic::Ordering::Relaxed);
+}
+
+pub fn ascii() -> bool {
+    ASCII.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+/// Whether the locale says the terminal can't be trusted with anything but ASCII. The first of
+/// `LC_ALL`, `LC_CTYPE` and `LANG` that's set decides, and nothing being set means the C locale.
+#[cfg(unix)]
+pub fn locale_is_ascii() -> bool {
+    let locale = ["LC_ALL", "LC_CTYPE", "LANG"].iter()
+        .filter_map(|var| std::env::var(var).ok())
+        .find(|value| !value.is_empty())
+        .unwrap_or_default()
+        .to_lowercase();
+
+    !locale.contains("utf-8") && !locale.contains("utf8")
+}
+
+/// Windows consoles don't advertise their encoding through the environment, assume they cope.
+#[cfg(not(unix))]
+pub fn locale_is_ascii() -> bool {
+    false
+}
+
+/// When to color output, picked with `--color`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorChoice {
+    /// Only on a terminal, and only if `$NO_COLOR` isn't set
+    Auto,
+    Always,
+    Never,
+}
+impl std::str::FromStr for ColorChoice {
+    type Err = failure::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(invalid_argument!("Expected a color choice of auto, always or never, got {}", s)),
+        }
+    }
+}
+
+static COLOR: std::sync::Mutex<ColorChoice> = std::sync::Mutex::new(ColorChoice::Auto);
+
+/// Set by `--color`, forced to `Never` for JSON and the other machine readable formats.
+pub fn set_color(choice: ColorChoice) {
+    *COLOR.lock().unwrap() = choice;
+}
+
+/// Whether to color what's written to stdout, or stderr if `stdout` isn't set.
+fn color(stdout: bool) -> bool {
+    match *COLOR.lock().unwrap() {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => {
+            // https://no-color.org, set at all (to anything but nothing) means no color
+            let no_color = std::env::var_os("NO_COLOR").is_some_and(|v| !v.is_empty());
+            let dumb = std::env::var("TERM").is_ok_and(|term| term == "dumb");
+            let tty = if stdout {
+                OUTPUT.lock().unwrap().is_none() && atty::is(atty::Stream::Stdout)
+            } else {
+                atty::is(atty::Stream::Stderr)
+            };
+
+            !no_color && !dumb && tty
+        },
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Style {
+    Bold,
+    Red,
+    Green,
+    Cyan,
+}
+impl Style {
+    fn code(self) -> &'static str {
+        match self {
+            Style::Bold => "1",
+            Style::Red => "31",
+            Style::Green => "32",
+            Style::Cyan => "36",
+        }
+    }
+}
+
+/// `text` in `style` when results are being colored. Display impls should go through this rather
+/// than writing escape codes themselves so piped output never gets any.
+pub fn paint(style: Style, text: &str) -> String {
+    paint_if(color(true), style, text)
+}
+
+/// Same as `paint`, for text written to stderr.
+pub fn paint_stderr(style: Style, text: &str) -> String {
+    paint_if(color(false), style, text)
+}
+
+fn paint_if(color: bool, style: Style, text: &str) -> String {
+    if color && !text.is_empty() {
+        format!("\x1b[{}m{}\x1b[0m", style.code(), text)
+    } else {
+        text.to_string()
+    }
+}
+
+/// Every pictogram and box drawing character used in human readable output. Output code should
+/// always go through `glyph` rather than writing these out itself so `--ascii` can't miss any.
+#[derive(Debug, Clone, Copy)]
+pub enum Glyph {
+    Artist,
+    Title,
+    Album,
+    Elapsed,
+    Volume,
+    Muted,
+    Battery,
+    Room,
+    Clock,
+    Favorite,
+    Waiting,
+    Warning,
+    Playing,
+    Paused,
+    /// Narrow versions for `status --oneline`, which has no room for labels
+    Play,
+    Pause,
+    Note,
+    Ellipsis,
+    /// Between an artist and a title
+    Dash,
+    /// Between a name and what it's doing
+    LongDash,
+    Arrow,
+    Branch,
+    LastBranch,
+    Trunk,
+}
+
+/// The text for a glyph, a plain label in ASCII mode. Labels that start a line include the
+/// space after them.
+pub fn glyph(glyph: Glyph) -> &'static str {
+    use Glyph::*;
+
+    let (unicode, plain) = match glyph {
+        Artist => ("\u{1F3A4}  ", "artist: "),
+        Title => ("\u{1F3B5}  ", "title: "),
+        Album => ("\u{1F4BF}  ", "album: "),
+        Elapsed => ("\u{23F1}\u{FE0F}  ", "time: "),
+        Volume => ("\u{1F50A} ", "vol: "),
+        Muted => ("\u{1F507} ", "muted: "),
+        Battery => ("\u{1F50B} ", "battery: "),
+        Room => ("\u{1F508}  ", ""),
+        Clock => ("\u{1F552}  ", ""),
+        Favorite => ("\u{2B50} ", "* "),
+        Waiting => ("\u{23F2}\u{FE0F}  ", ""),
+        Warning => ("\u{26A0}\u{FE0F}  ", "! "),
+        Playing => ("\u{25B6}\u{FE0F}  ", "playing: "),
+        Paused => ("\u{23F8}\u{FE0F}  ", "paused: "),
+        Play => ("\u{25B6}", ">"),
+        Pause => ("\u{23F8}", "||"),
+        Note => ("\u{266A}", "vol "),
+        Ellipsis => ("\u{2026}", "..."),
+        Dash => ("\u{2013}", "-"),
+        LongDash => ("\u{2014}", "-"),
+        Arrow => ("\u{2192}", "->"),
+        Branch => ("\u{251C}\u{2500}", "|-"),
+        LastBranch => ("\u{2514}\u{2500}", "`-"),
+        Trunk => ("\u{2502}", "|"),
+    };
+
+    if ascii() { plain } else { unicode }
+}
+
+/// A `[▇▇▇---]` bar `len` characters wide, `filled` of which are filled in.
+pub fn progress_bar(filled: usize, len: usize) -> String {
+    let filled = filled.min(len);
+
+    format!("[{}{}]", if ascii() { "#" } else { "\u{2587}" }.repeat(filled), "-".repeat(len - filled))
+}
+
+/// `text` cut down to at most `width` characters, with an ellipsis on the end if anything was
+/// cut.
+pub fn truncate(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_string();
+    }
+
+    let ellipsis = glyph(Glyph::Ellipsis);
+    let kept = width.saturating_sub(ellipsis.chars().count());
+
+    format!("{}{}", text.chars().take(kept).collect::<String>().trim_end(), if width >= ellipsis.chars().count() { ellipsis } else { "" })
+}
+
+/// How wide the terminal is, `None` if output isn't going to one and lines can be as long as
+/// they like. `$COLUMNS` wins when it's set, otherwise the terminal on stdout is asked.
+pub fn terminal_width() -> Option<usize> {
+    if let Some(columns) = std::env::var("COLUMNS").ok().and_then(|c| c.trim().parse().ok()) {
+        return Some(columns);
+    }
+
+    if OUTPUT.lock().unwrap().is_some() {
+        return None;
+    }
+
+    terminal_size::terminal_size().map(|(terminal_size::Width(width), _)| usize::from(width))
+}
+
+/// The columns picked with `--columns`, a comma separated list of names from `available`. All
+/// of `default` if nothing was picked.
+pub fn columns(available: &[&'static str], default: &[&'static str], selected: Option<&str>) -> failure::Fallible<Vec<&'static str>> {
+    let selected = match selected {
+        Some(selected) => selected,
+        None => return Ok(default.to_vec()),
+    };
+
+    let columns = selected.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| available.iter()
+            .find(|column| column.eq_ignore_ascii_case(name))
+            .copied()
+            .ok_or_else(|| invalid_argument!("Unknown column '{}', expected one of: {}", name, available.join(", "))))
+        .collect::<failure::Fallible<Vec<_>>>()?;
+
+    if columns.is_empty() {
+        return Err(invalid_argument!("Expected at least one column, pick from: {}", available.join(", ")));
+    }
+
+    Ok(columns)
+}
+
+/// Rows of text lined up in columns under a header, for `--table`. Columns are as wide as their
+/// widest cell, with the widest shrunk first if that won't fit in the terminal.
+pub struct Table {
+    pub headers: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+impl std::fmt::Display for Table {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        const GAP: usize = 2;
+        const MIN_WIDTH: usize = 4;
+
+        let mut widths = self.headers.iter().map(|h| h.chars().count()).collect::<Vec<_>>();
+
+        for row in &self.rows {
+            for (width, cell) in widths.iter_mut().zip(row) {
+                *width = (*width).max(cell.chars().count());
+            }
+        }
+
+        if let Some(max) = terminal_width() {
+            let gaps = GAP * widths.len().saturating_sub(1);
+
+            while widths.iter().sum::<usize>() + gaps > max {
+                match widths.iter_mut().filter(|w| **w > MIN_WIDTH).max() {
+                    Some(widest) => *widest -= 1,
+                    None => break,
+                }
+            }
+        }
+
+        for (i, row) in std::iter::once(&self.headers).chain(&self.rows).enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+
+            let cells = row.iter()
+                .zip(&widths)
+                .map(|(cell, width)| format!("{:width$}", truncate(cell, *width), width = width))
+                .collect::<Vec<_>>();
+
+            // no padding after the last column, it'd only be trailing whitespace
+            write!(f, "{}", cells.join(&" ".repeat(GAP)).trim_end())?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A title prefixed with `glyph` and underlined.
+pub fn heading(glyph: Glyph, title: &str) -> String {
+    // the pictograms are two columns wide, followed by two spaces
+    let width = if ascii() { self::glyph(glyph).len() } else { 3 } + title.chars().count();
+
+    format!("{}{}\n{}", self::glyph(glyph), title, "=".repeat(width))
+}
+
+static ASSUME_YES: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Answer yes to every `confirm` prompt without asking, set by `--yes`.
+pub fn set_assume_yes(yes: bool) {
+    ASSUME_YES.store(yes, std::sync::atomic::Ordering::Relaxed);
+}
+
+static NO_INPUT: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Never prompt, fail instead, set by `--no-input`.
+pub fn set_no_input(no_input: bool) {
+    NO_INPUT.store(no_input, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Whether there's somebody at a terminal to answer prompts.
+fn interactive() -> bool {
+    !NO_INPUT.load(std::sync::atomic::Ordering::Relaxed) && atty::is(atty::Stream::Stdin)
+}
+
+/// Ask a yes/no question on the terminal. When stdin isn't a terminal there's nobody to ask so
+/// this fails straight away rather than blocking a script forever, unless `--yes` was given.
+/// Prompts go to stderr so they don't end up in captured output.
+pub async fn confirm(question: &str) -> failure::Fallible<bool> {
+    ask(question, true).await
+}
+
+/// Same as `confirm`, but for things that are hard to undo so only an explicit yes will do.
+pub async fn confirm_destructive(question: &str) -> failure::Fallible<bool> {
+    ask(question, false).await
+}
+
+async fn ask(question: &str, default: bool) -> failure::Fallible<bool> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    if ASSUME_YES.load(std::sync::atomic::Ordering::Relaxed) {
+        return Ok(true);
+    }
+
+    if !interactive() {
+        bail!("{} Not asking since prompts are disabled or stdin isn't a terminal, pass --yes to accept", question);
+    }
+
+    let mut stderr = tokio::io::stderr();
+    stderr.write_all(format!("{} {} ", question, if default { "[Y/n]" } else { "[y/N]" }).as_bytes()).await?;
+    stderr.flush().await?;
+
+    let mut input = String::new();
+    tokio::io::BufReader::new(tokio::io::stdin()).read_line(&mut input).await?;
+
+    // trimming takes care of \r\n line endings, and just hitting enter takes the default
+    Ok(match input.trim().to_lowercase().chars().next() {
+        None => default,
+        Some(c) => c == 'y',
+    })
+}
+
+/// Ask which of `options` was meant, returning its index. Returns `None` without asking when
+/// stdin isn't a terminal or `--no-input` was given, so the caller can fail with something more
+/// useful.
+pub async fn choose(question: &str, options: &[String]) -> failure::Fallible<Option<usize>> {
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt};
+
+    if !interactive() {
+        return Ok(None);
+    }
+
+    let mut prompt = format!("{}\n", question);
+
+    for (i, option) in options.iter().enumerate() {
+        prompt.push_str(&format!("  {}) {}\n", i + 1, option));
+    }
+
+    prompt.push_str(&format!("Choose 1-{}: ", options.len()));
+
+    let mut stderr = tokio::io::stderr();
+    stderr.write_all(prompt.as_bytes()).await?;
+    stderr.flush().await?;
+
+    let mut input = String::new();
+    tokio::io::BufReader::new(tokio::io::stdin()).read_line(&mut input).await?;
+
+    match input.trim().parse::<usize>() {
+        Ok(choice) if (1..=options.len()).contains(&choice) => Ok(Some(choice - 1)),
+        _ => Err(invalid_argument!("Expected a number between 1 and {}, got '{}'", options.len(), input.trim())),
+    }
+}
+
+/// How sure `fuzzy_match` is that it found what was meant.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Confidence {
+    /// The name is exactly what was asked for, ignoring case and surrounding whitespace
+    Exact,
+    /// Obviously what was meant: each word asked for starts the name's words, the name contains
+    /// what was asked for or it's a couple of typos away
+    Close,
+    /// Could be what was meant, worth asking before going ahead with it
+    Fuzzy,
+    /// Nothing comes anywhere near
+    None,
+}
+
+pub struct MatchResult<'a, T> {
+    pub confidence: Confidence,
+    /// Every candidate that matched equally well at the best confidence found, sorted by name.
+    /// Candidates sharing a name (ie. both halves of a stereo pair) are only included once.
+    pub matches: Vec<&'a T>,
+}
+
+/// Find the candidates whose name best matches `query`, the same way room names are matched.
+pub fn fuzzy_match<'a, T>(candidates: &'a [T], query: &str, name: impl Fn(&T) -> &str) -> MatchResult<'a, T> {
+    // nobody remembers how their rooms are capitalised
+    let normalise = |s: &str| s.trim().to_lowercase();
+    let wanted = normalise(query);
+
+    let result = |confidence, mut matches: Vec<&'a T>| {
+        matches.sort_by(|a, b| name(a).cmp(name(b)));
+        matches.dedup_by(|a, b| name(a) == name(b));
+
+        MatchResult { confidence, matches }
+    };
+
+    // an exact match always wins, however close other names are
+    let exact = candidates.iter()
+        .filter(|c| normalise(name(c)) == wanted)
+        .collect::<Vec<_>>();
+
+    if !exact.is_empty() {
+        return result(Confidence::Exact, exact);
+    }
+
+    // "bed 2" for "Bedroom 2", each word given starts the same word of the name
+    let prefixed = candidates.iter()
+        .filter(|c| is_word_prefix(&wanted, &normalise(name(c))))
+        .collect::<Vec<_>>();
+
+    if !prefixed.is_empty() {
+        return result(Confidence::Close, prefixed);
+    }
+
+    // "living" for "Living Room" is way past the edit distance threshold but obviously intended
+    let containing = candidates.iter()
+        .filter(|c| !wanted.is_empty() && normalise(name(c)).contains(&wanted))
+        .collect::<Vec<_>>();
+
+    if !containing.is_empty() {
+        return result(Confidence::Close, containing);
+    }
+
+    let distance = |c: &T| strsim::damerau_levenshtein(&normalise(name(c)), &wanted);
+    let min = candidates.iter().map(distance).min().unwrap_or(usize::MAX);
+
+    let closest = candidates.iter()
+        .filter(|c| distance(c) == min)
+        .collect();
+
+    match min {
+        0..=2 => result(Confidence::Close, closest),
+        3..=5 => result(Confidence::Fuzzy, closest),
+        _ => result(Confidence::None, Vec::new()),
+    }
+}
+
+/// Whether each word of `wanted` starts the word in the same position of `name`.
+fn is_word_prefix(wanted: &str, name: &str) -> bool {
+    let mut name = name.split_whitespace();
+
+    !wanted.is_empty() && wanted.split_whitespace().all(|word| name.next().is_some_and(|n| n.starts_with(word)))
+}
+
 pub fn duration_to_hms(d: std::time::Duration) -> String {
     let mut s = String::new();
 
@@ -26,7 +802,258 @@ pub fn duration_to_hms(d: std::time::Duration) -> String {
     s
 }
 
-pub fn setup_logger() -> Result<(), fern::InitError> {
+static RAW_SECONDS: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Serialise durations as whole seconds rather than `hh:mm:ss` strings, set by `--raw-seconds`.
+pub fn set_raw_seconds(raw: bool) {
+    RAW_SECONDS.store(raw, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// `serialize_with` helper for `Duration` fields in JSON output, formats them the same way as
+/// the human readable output (`duration_to_hms`) instead of serde's `{secs, nanos}`.
+pub fn serialize_duration<S: serde::Serializer>(d: &std::time::Duration, serializer: S) -> Result<S::Ok, S::Error> {
+    if RAW_SECONDS.load(std::sync::atomic::Ordering::Relaxed) {
+        serializer.serialize_u64(d.as_secs())
+    } else {
+        serializer.serialize_str(&duration_to_hms(*d))
+    }
+}
+
+/// `serialize_with` helper for `Option<Duration>` fields in JSON output.
+pub fn serialize_optional_duration<S: serde::Serializer>(d: &Option<std::time::Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+    match d {
+        Some(d) => serialize_duration(d, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Every shape a duration has been written out as, so JSON from `--raw-seconds`, the default
+/// `hh:mm:ss` and older versions' `{secs, nanos}` can all be read back.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AnyDuration {
+    Seconds(u64),
+    Hms(String),
+    Legacy { secs: u64, nanos: u32 },
+}
+impl AnyDuration {
+    fn into_duration<E: serde::de::Error>(self) -> Result<std::time::Duration, E> {
+        match self {
+            AnyDuration::Seconds(secs) => Ok(std::time::Duration::from_secs(secs)),
+            AnyDuration::Hms(hms) => hms_to_duration(&hms).map_err(E::custom),
+            AnyDuration::Legacy { secs, nanos } => Ok(std::time::Duration::new(secs, nanos)),
+        }
+    }
+}
+
+/// `deserialize_with` helper for `Duration` fields written by `serialize_duration`, takes whole
+/// seconds or `hh:mm:ss`.
+pub fn deserialize_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<std::time::Duration, D::Error> {
+    serde::Deserialize::deserialize(deserializer).and_then(AnyDuration::into_duration)
+}
+
+/// `deserialize_with` helper for `Option<Duration>` fields, needs `#[serde(default)]` alongside
+/// it for the field to be optional.
+pub fn deserialize_optional_duration<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<std::time::Duration>, D::Error> {
+    let duration: Option<AnyDuration> = serde::Deserialize::deserialize(deserializer)?;
+
+    duration.map(AnyDuration::into_duration).transpose()
+}
+
+/// Same as `duration_to_hms` without the padding, eg. `2:11` or `1:02:11`, for tight spaces.
+pub fn duration_to_short(d: std::time::Duration) -> String {
+    let secs = d.as_secs();
+
+    match secs / 3600 {
+        0 => format!("{}:{:02}", secs / 60, secs % 60),
+        hours => format!("{}:{:02}:{:02}", hours, secs % 3600 / 60, secs % 60),
+    }
+}
+
+/// Same as `duration_to_hms` with milliseconds added on when there are any, eg. `00:01.500`.
+pub fn duration_to_hms_precise(d: std::time::Duration) -> String {
+    let mut s = duration_to_hms(d);
+
+    if d.subsec_millis() > 0 {
+        s.push_str(&format!(".{:03}", d.subsec_millis()));
+    }
+
+    s
+}
+
+/// Parse a `hh:mm:ss`/`mm:ss`/`ss` timestamp into a `Duration`.
+pub fn hms_to_duration(s: &str) -> failure::Fallible<std::time::Duration> {
+    let mut secs = 0;
+
+    for section in s.split(':') {
+        secs = secs * 60 + section.trim().parse::<u64>()
+            .map_err(|_| invalid_argument!("Invalid timestamp '{}', expected hh:mm:ss or mm:ss", s))?;
+    }
+
+    Ok(std::time::Duration::from_secs(secs))
+}
+
+enum Piece {
+    Literal(String),
+    Field(String),
+}
+
+/// A `--format` template, text with `{name}` placeholders filled in from a result's fields.
+/// `{{` and `}}` are literal braces.
+pub struct Template(Vec<Piece>);
+impl Template {
+    /// Parse `template`, failing if it uses a placeholder that isn't one of `fields` so typos
+    /// are caught before anything is sent to a speaker.
+    pub fn parse(template: &str, fields: &[&str]) -> failure::Fallible<Template> {
+        let mut pieces = Vec::new();
+        let mut literal = String::new();
+        let mut chars = template.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                },
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                },
+                '{' => {
+                    let mut name = String::new();
+
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => name.push(c),
+                            None => bail!("Unclosed '{' in format, use '{{' for a literal brace"),
+                        }
+                    }
+
+                    let name = name.trim();
+
+                    if !fields.contains(&name) {
+                        return Err(invalid_argument!("Unknown placeholder '{{{}}}' in format, expected one of: {}", name, fields.join(", ")));
+                    }
+
+                    pieces.push(Piece::Literal(std::mem::take(&mut literal)));
+                    pieces.push(Piece::Field(name.to_string()));
+                },
+                '}' => bail!("Unmatched '}' in format, use '}}' for a literal brace"),
+                c => literal.push(c),
+            }
+        }
+
+        pieces.push(Piece::Literal(literal));
+
+        Ok(Template(pieces))
+    }
+
+    /// Whether the template uses `field`, for fields that take an extra request to fill in.
+    pub fn uses(&self, field: &str) -> bool {
+        self.0.iter().any(|piece| matches!(piece, Piece::Field(name) if name == field))
+    }
+
+    /// Fill the template in from `value`'s serialised fields, with `derived` taking precedence.
+    /// Missing fields and `None`s are left empty.
+    pub fn render(&self, value: &impl serde::Serialize, derived: &[(&str, String)]) -> failure::Fallible<String> {
+        let value = serde_json::to_value(value)?;
+
+        Ok(self.0.iter()
+            .map(|piece| match piece {
+                Piece::Literal(text) => text.clone(),
+                Piece::Field(name) => derived.iter()
+                    .find(|(field, _)| field == name)
+                    .map(|(_, value)| value.clone())
+                    .unwrap_or_else(|| match value.get(name) {
+                        None | Some(serde_json::Value::Null) => String::new(),
+                        Some(serde_json::Value::String(s)) => s.clone(),
+                        Some(other) => other.to_string(),
+                    }),
+            })
+            .collect())
+    }
+}
+
+static TIMING: std::sync::atomic::AtomicBool = std::sync::atomic::AtomicBool::new(false);
+
+/// Print how long each step of the command takes to stderr, set by `--timing`.
+pub fn set_timing(timing: bool) {
+    TIMING.store(timing, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// Print how long `label` took, if `--timing` was given.
+pub fn report_timing(label: &str, elapsed: std::time::Duration) {
+    if TIMING.load(std::sync::atomic::Ordering::Relaxed) {
+        eprintln!("{}: {}ms", label, elapsed.as_millis());
+    }
+}
+
+/// Run `fut`, reporting how long it took if `--timing` was given.
+pub async fn timed<T>(label: &str, fut: impl std::future::Future<Output = T>) -> T {
+    let start = std::time::Instant::now();
+    let result = fut.await;
+
+    report_timing(label, start.elapsed());
+
+    result
+}
+
+/// Fail with a timeout error if `fut` doesn't complete within `duration`, rather than hanging on
+/// the TCP connect to a speaker that's been unplugged since it was cached.
+pub async fn timeout<T>(
+    duration: std::time::Duration,
+    fut: impl std::future::Future<Output = failure::Fallible<T>>,
+) -> failure::Fallible<T> {
+    tokio::time::timeout(duration, fut).await
+        .unwrap_or_else(|_| Err(TimedOut(duration.as_secs()).into()))
+}
+
+/// Run `f` until it succeeds, retrying it up to `retries` more times with exponential backoff
+/// when it fails with an error that's likely to go away on its own (dropped connections, 5xx
+/// responses). Any other error is returned straight away, as is an attempt that takes longer
+/// than `timeout` since the speaker is most likely gone.
+///
+/// Only wrap operations that are safe to run twice, the speaker may have acted on a request we
+/// never got the response to.
+pub async fn retry<T, F, Fut>(retries: u32, timeout: std::time::Duration, mut f: F) -> failure::Fallible<T>
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = failure::Fallible<T>>,
+{
+    let mut attempt = 0;
+
+    loop {
+        match self::timeout(timeout, f()).await {
+            Err(e) if attempt < retries && is_transient(&e) => {
+                let backoff = std::time::Duration::from_millis(250 * 2u64.pow(attempt));
+                debug!("{}, retrying in {}", e, duration_to_hms_precise(backoff));
+
+                tokio::time::delay_for(backoff).await;
+                attempt += 1;
+            },
+            result => return result,
+        }
+    }
+}
+
+fn is_transient(e: &failure::Error) -> bool {
+    if let Some(e) = e.downcast_ref::<reqwest::Error>() {
+        e.is_timeout() || e.is_connect() || e.is_request() || e.status().is_some_and(|s| s.is_server_error())
+    } else if let Some(e) = e.downcast_ref::<crate::upnp::HttpError>() {
+        e.status >= 500
+    } else if let Some(e) = e.downcast_ref::<std::io::Error>() {
+        use std::io::ErrorKind::*;
+
+        matches!(e.kind(), ConnectionReset | ConnectionAborted | ConnectionRefused | TimedOut | UnexpectedEof)
+    } else {
+        false
+    }
+}
+
+/// Log to stderr, warnings only unless `verbose` is set. Results don't go through here, see
+/// `output`.
+pub fn setup_logger(verbose: bool) -> Result<(), fern::InitError> {
     fern::Dispatch::new()
         .format(|out, message, _record| {
             out.finish(format_args!(
@@ -34,8 +1061,74 @@ pub fn setup_logger() -> Result<(), fern::InitError> {
                 message
             ))
         })
-        .level(log::LevelFilter::Info)
-        .chain(std::io::stdout())
+        .level(if verbose { log::LevelFilter::Debug } else { log::LevelFilter::Warn })
+        .chain(std::io::stderr())
         .apply()?;
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::Duration;
+
+    #[test]
+    fn duration_to_hms_precise_adds_millis() {
+        assert_eq!(duration_to_hms_precise(Duration::from_millis(1500)), "00:01.500");
+    }
+
+    #[test]
+    fn duration_to_hms_precise_includes_hours() {
+        assert_eq!(duration_to_hms_precise(Duration::from_secs(60 * 60)), "01:00:00");
+    }
+
+    #[test]
+    fn duration_to_hms_precise_zero() {
+        assert_eq!(duration_to_hms_precise(Duration::from_secs(0)), "00:00");
+    }
+
+    #[test]
+    fn to_json_is_one_line_without_pretty() {
+        let json = to_json(&serde_json::json!({ "volume": 20, "track": { "title": "Song" } })).unwrap();
+
+        assert!(!json.contains('\n'), "{}", json);
+    }
+
+    #[test]
+    fn columns_rejects_an_empty_selection() {
+        assert!(columns(&["title", "artist"], &["title"], Some("")).is_err());
+        assert!(columns(&["title", "artist"], &["title"], Some(" , ")).is_err());
+        assert_eq!(columns(&["title", "artist"], &["title"], Some("Artist")).unwrap(), vec!["artist"]);
+    }
+
+    const ROOMS: [&str; 5] = ["Bedroom 1", "Bedroom 2", "Kitchen", "Living Room", "Office"];
+
+    fn matched(query: &str) -> (Confidence, Vec<&'static str>) {
+        let result = fuzzy_match(&ROOMS, query, |room| room);
+
+        (result.confidence, result.matches.into_iter().copied().collect())
+    }
+
+    #[test]
+    fn fuzzy_match_exact() {
+        assert_eq!(matched("Kitchen"), (Confidence::Exact, vec!["Kitchen"]));
+    }
+
+    #[test]
+    fn fuzzy_match_ignores_case() {
+        assert_eq!(matched(" living ROOM "), (Confidence::Exact, vec!["Living Room"]));
+    }
+
+    #[test]
+    fn fuzzy_match_typos() {
+        assert_eq!(matched("kitchn"), (Confidence::Close, vec!["Kitchen"]));
+        assert_eq!(matched("kitcheeeen"), (Confidence::Fuzzy, vec!["Kitchen"]));
+        assert_eq!(matched("garage door"), (Confidence::None, vec![]));
+    }
+
+    #[test]
+    fn fuzzy_match_ambiguous() {
+        assert_eq!(matched("bed"), (Confidence::Close, vec!["Bedroom 1", "Bedroom 2"]));
+    }
+}
@@ -0,0 +1,63 @@
+use std::net::IpAddr;
+use std::time::Duration;
+
+use sonos::Speaker;
+use failure::Fallible;
+use futures::future::Either;
+
+use crate::upnp::{self, Service};
+
+/// Shortest gap between two volume changes during a ramp, any faster and the speaker struggles
+/// to keep up.
+const MIN_STEP: Duration = Duration::from_millis(250);
+
+/// Parse a volume from the command line, making sure it's within 0-100.
+pub fn parse(volume: &str) -> Fallible<u8> {
+    match volume.parse::<u8>() {
+        Ok(volume) if volume <= 100 => Ok(volume),
+        _ => Err(invalid_argument!("Expected a volume between 0 and 100, got {}", volume)),
+    }
+}
+
+/// Set the speaker's own volume, rather than its group's.
+pub async fn set(ip: IpAddr, volume: u8) -> Fallible<()> {
+    upnp::call(ip, Service::RenderingControl, "SetVolume", &[
+        ("InstanceID", "0"),
+        ("Channel", "Master"),
+        ("DesiredVolume", &volume.to_string()),
+    ]).await?;
+
+    Ok(())
+}
+
+/// Gradually move the speaker's volume from `from` to `to` over `over`, one step at a time.
+/// Returns `false` if the ramp was stopped part way through with Ctrl-C.
+pub async fn ramp(speaker: &Speaker, from: u8, to: u8, over: Duration) -> Fallible<bool> {
+    let steps = u32::from(from.abs_diff(to))
+        .min((over.as_millis() / MIN_STEP.as_millis()) as u32)
+        .max(1);
+
+    let ramp = async {
+        set(speaker.ip, from).await?;
+
+        for step in 1..=steps {
+            // nothing's changing under --dry-run, so there's nothing to wait for either
+            if !upnp::dry_run() {
+                tokio::time::delay_for(over / steps).await;
+            }
+
+            let volume = i64::from(from) + (i64::from(to) - i64::from(from)) * i64::from(step) / i64::from(steps);
+            set(speaker.ip, volume as u8).await?;
+        }
+
+        Ok::<(), failure::Error>(())
+    };
+
+    let ctrl_c = tokio::signal::ctrl_c();
+    futures::pin_mut!(ramp, ctrl_c);
+
+    match futures::future::select(ramp, ctrl_c).await {
+        Either::Left((result, _)) => result.map(|_| true),
+        Either::Right(_) => Ok(false),
+    }
+}